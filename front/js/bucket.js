@@ -2,6 +2,7 @@
 
 const FILE_ACTIONS = {
     'Open 🡽': file_new_tab,
+    'Download': file_download,
     'Delete': file_delete,
 };
 
@@ -13,11 +14,19 @@ function size_fmt(num_bytes) {
     else return num_bytes.toString() + ' B';
 }
 
+// distinct files are counted once
+function bucket_usage(files) {
+    let sizes = {};
+    files.forEach(file => sizes[file.sha256] = file.size);
+    return Object.values(sizes).reduce((a, b) => a + b, 0);
+}
+
 async function init_bucket(side_i) {
     let side = SIDES[side_i];
-    let [_, [rev, files]] = await request('load-bucket', side.raw_id);
+    let [_, [rev, files, quota]] = await request('load-bucket', side.raw_id);
     side.revision = rev;
     side.files = files;
+    side.quota = quota;
 
     await init_banner(side_i);
 
@@ -25,7 +34,7 @@ async function init_bucket(side_i) {
     side.files_div = create(side.element, 'div', div_c);
     side.files_div.side_i = side_i;
 
-    let actions = { 'Upload': upload, };
+    let actions = { 'Upload': upload, 'Set Quota': set_bucket_quota, };
     init_context_menu(side.files_div, actions);
 
     for (let i = 0; i < files.length; i++) {
@@ -48,10 +57,17 @@ async function init_bucket(side_i) {
 
 async function file_new_tab() {
     let side = SIDES[this.parentElement.side_i];
-    let data = side.files[this.index];
+    // opened right away, as popups can't be opened after an await
+    let tab = window.open('', '_blank');
+    let [_, url] = await request('file-link', [side.raw_id, this.index]);
+    tab.location = url;
+}
+
+async function file_download() {
+    let side = SIDES[this.parentElement.side_i];
+    let [_, url] = await request('file-link', [side.raw_id, this.index]);
     let anchor = document.createElement('a');
-    anchor.href = '/files/' + data.sha256 + '/' + data.name;
-    anchor.target = '_blank';
+    anchor.href = url + '&disposition=attachment';
     anchor.click();
 }
 
@@ -60,6 +76,24 @@ async function file_delete() {
     let side = SIDES[this.parentElement.side_i];
     let parameters = [side.raw_id, side.revision, this.index];
     let _ = await request('delete-file', parameters);
+    await load_user_data();
+}
+
+async function set_bucket_quota() {
+    let side = SIDES[this.side_i];
+    let text = 'Used: ' + size_fmt(bucket_usage(side.files));
+    if (side.quota !== null) text += ' of ' + size_fmt(side.quota);
+    text += '\nNew quota in MB (leave empty for none):';
+
+    let input = prompt(text);
+    if (input === null) return;
+
+    let quota = input.trim() ? Math.round(parseFloat(input) * 10**6) : null;
+    try {
+        let _ = await request('set-bucket-quota', [side.raw_id, quota]);
+    } catch (e) {
+        alert(e);
+    }
 }
 
 async function upload() {
@@ -86,13 +120,25 @@ async function file_pick() {
             continue;
         }
 
+        let storage = USER_DATA.secret.storage;
+        if (storage.used + file.size > USER_DATA.secret.storage_quota) {
+            alert(file.name + ': quota de stockage dépassé.');
+            continue;
+        }
+
         for (let j = 0; j < file.size; j += CHUNK) {
             let chunk = file.slice(j, j + CHUNK);
             SOCKET.send(chunk);
         }
 
         let parameters = [side.raw_id, side.revision, file.name];
-        let _ = await request('finish-file', parameters);
+        try {
+            let _ = await request('finish-file', parameters);
+        } catch (e) {
+            alert(file.name + ': ' + e);
+        }
+
+        await load_user_data();
     }
 
     CAN_CLOSE_POPUP = true;
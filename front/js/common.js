@@ -1,6 +1,7 @@
 // common.js
 
 const CHUNK = 4096;
+const RECONNECT_DELAY = 2000;
 let LEFT_PANEL_ITEMS;
 let CONTEXT_MENU;
 let MAIN_PANEL;
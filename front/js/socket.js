@@ -60,6 +60,23 @@ async function handle_update(update) {
                 await load_user_data();
                 await refresh_left_panel();
             }
+        } else if (update.type === 'bye-invite') {
+            // maybe opened on another device
+            await load_user_data();
+            await refresh_left_panel();
+        } else if (update.type === 'token-revoked') {
+            // this device was logged out
+            forget_token();
+            location.reload();
+        } else if (update.type === 'front-reload') {
+            // the server was started with KOLAB_RELOAD_CLIENTS
+            location.reload();
+        } else if (update.type === 'server-shutdown') {
+            // the session will be resumed once the server is back
+            console.log('server going down');
+            if (notif_enabled) {
+                new Notification('Server restarting', { body: 'Reconnecting shortly...' });
+            }
         }
 
         return;
@@ -91,13 +108,19 @@ async function handle_update(update) {
         side.revision = update.new_revision;
         set_message(side_i, message);
         await update_last_seen(side_i);
-    } else if (update.type === 'new-guest' || update.type === 'bye-guest') {
+    } else if (['new-guest', 'bye-guest', 'new-owner'].includes(update.type)) {
+        if (side) side.revision = update.new_revision;
         await load_user_data();
         await refresh_left_panel();
-        if (side) await init_banner(side_i);
-
-        if (notif_enabled) {
-            let suffix = (update.type === 'new-guest') ? ' joined!' : ' left';
+        if (side && USER_DATA.secret.entities[update.id]) await init_banner(side_i);
+        else if (side) close_side(side_i);
+
+        if (notif_enabled && USER_DATA.secret.entities[update.id]) {
+            let suffix = {
+                'new-guest': ' joined!',
+                'bye-guest': ' left',
+                'new-owner': ' is the new owner',
+            }[update.type];
             let text = await get_username(update.data) + suffix;
             let title = USER_DATA.secret.entities[update.id].local_name;
             new Notification(title, { body: text });
@@ -126,7 +149,7 @@ async function handle_update(update) {
             side.elements.splice(update.index, 0, update.data);
         }
         await update_last_seen(side_i);
-    } else if (update.type.endsWith('-file') && side) {
+    } else if ((update.type.endsWith('-file') || update.type === 'set-bucket-quota') && side) {
         side.revision = update.new_revision;
         await open_entity(update.id);
     }
@@ -149,16 +172,53 @@ function request(request, parameters) {
     return new Promise(setup);
 }
 
+async function sync_side(side_i) {
+    let side = SIDES[side_i];
+    if (side.entity_id === undefined) return;
+
+    let parameters = [side.entity_id, side.revision];
+    let [reply, data] = await request('sync-since', parameters);
+
+    if (reply === 'updates') {
+        let [_rev, updates] = data;
+        for (let i = 0; i < updates.length; i++) {
+            await handle_update(updates[i]);
+        }
+    } else {
+        // too far behind
+        await open_entity(side.entity_id);
+    }
+}
+
+async function resume_session() {
+    try {
+        await login_auth();
+        await sync_side(SIDE_L);
+        await sync_side(SIDE_R);
+    } catch {
+        if (confirm("Connection failure! Reload page?")) location.reload();
+    }
+}
+
 function ws_disconnected() {
-    if (confirm("Connection failure! Reload page?")) location.reload();
+    SOCKET.removeEventListener('close', ws_disconnected);
+    SOCKET.removeEventListener('error', ws_disconnected);
+
+    // pending requests will never get a reply
+    CALLBACKS.forEach(callback => callback.reject('disconnected'));
+    CALLBACKS = [];
+
+    let on_open = (USER_ID === undefined) ? try_auto_login : resume_session;
+    setTimeout(() => init_websocket(on_open), RECONNECT_DELAY);
 }
 
-function init_websocket() {
+function init_websocket(on_open) {
     let protocol = document.location.protocol === 'http:' ? 'ws:' : 'wss:';
     let ws_url = protocol + '//' + document.location.host + '/session';
 
+    // browsers negotiate permessage-deflate on their own
     SOCKET = new WebSocket(ws_url);
-    SOCKET.addEventListener('open', try_auto_login);
+    SOCKET.addEventListener('open', on_open || try_auto_login);
     SOCKET.addEventListener('message', reply_callback);
     SOCKET.addEventListener('close', ws_disconnected);
     SOCKET.addEventListener('error', ws_disconnected);
@@ -6,6 +6,84 @@ async function shutdown_server() {
     }
 }
 
+function size_string(bytes) {
+    let units = ['B', 'KiB', 'MiB', 'GiB'];
+    let i = 0;
+    while (bytes >= 1024 && i + 1 < units.length) {
+        bytes /= 1024;
+        i += 1;
+    }
+
+    return (i ? bytes.toFixed(1) : bytes) + ' ' + units[i];
+}
+
+function show_storage() {
+    let used = USER_DATA.secret.storage.used;
+    let quota = USER_DATA.secret.storage_quota;
+    let percent = quota ? Math.round(100 * used / quota) : 100;
+    alert('Storage used: ' + size_string(used) + ' of ' + size_string(quota) + ' (' + percent + '%)');
+}
+
+async function show_server_stats() {
+    let [_, stats] = await request('server-stats', null);
+    alert([
+        'Users: ' + stats.users + ' (' + stats.disabled_users + ' disabled)',
+        'Connections: ' + stats.connections + ' (' + stats.sessions + ' logged in)',
+        'Conversations: ' + stats.conversations,
+        'Documents: ' + stats.documents,
+        'Spreadsheets: ' + stats.spreadsheets,
+        'Buckets: ' + stats.buckets,
+        'Abandoned entities: ' + stats.abandoned_entities,
+        'Files: ' + stats.stored_files + ' (' + size_string(stats.storage_bytes) + ')',
+        'Orphaned files: ' + stats.orphaned_files + ' (' + size_string(stats.orphaned_bytes) + ')',
+        'Database: ' + size_string(stats.database_bytes),
+        'Journal: ' + size_string(stats.journal_bytes),
+    ].join('\n'));
+}
+
+async function force_backup() {
+    let _ = await request('force-backup', null);
+    alert('Database snapshot scheduled.');
+}
+
+async function manage_user() {
+    let username = prompt('Username:');
+    if (!username) return;
+
+    try {
+        let [_a, user_id] = await request('who-is', username);
+        let [_b, users] = await request('list-users', null);
+        let user = users.find(user => user.id == user_id);
+
+        let text = user.name + (user.email ? ' <' + user.email + '>' : '') + '\n';
+        text += 'Max file size: ' + size_string(user.max_file_size) + '\n';
+        text += 'Storage: ' + size_string(user.storage_used) + ' of ' + size_string(user.storage_quota) + '\n';
+        text += 'Two-factor: ' + (user.two_factor ? 'enabled' : 'disabled') + '\n';
+        text += 'Sessions: ' + (user.sessions.join(', ') || 'none') + '\n\n';
+        text += 'Action (' + (user.disabled ? 'enable' : 'disable') + ', delete, max-size <MiB>, quota <MiB>):';
+
+        let action = (prompt(text) || '').trim().split(' ');
+        if (action[0] === 'disable' || action[0] === 'enable') {
+            let _ = await request('set-account-disabled', [user_id, action[0] === 'disable']);
+        } else if (action[0] === 'delete') {
+            if (!confirm('Delete ' + username + '? This cannot be undone.')) return;
+            let _ = await request('delete-account', user_id);
+        } else if (action[0] === 'max-size' && action[1]) {
+            let bytes = Math.round(parseFloat(action[1]) * 1024 * 1024);
+            let _ = await request('set-max-file-size', [user_id, bytes]);
+        } else if (action[0] === 'quota' && action[1]) {
+            let bytes = Math.round(parseFloat(action[1]) * 1024 * 1024);
+            let _ = await request('set-storage-quota', [user_id, bytes]);
+        } else {
+            return;
+        }
+
+        alert('Done.');
+    } catch (e) {
+        alert(e);
+    }
+}
+
 function refresh_invites_button() {
     let list_invites = find('list-invites');
     if (USER_DATA.secret.invites.length) {
@@ -36,11 +114,23 @@ async function load_user_data() {
             'Dark': switch_theme,
             'Elodie': switch_theme,
         },
+        'Storage': show_storage,
+        'Change Password': change_password,
+        'Two-Factor Authentication': manage_two_factor,
+        'Devices': show_devices,
         'Log Out': log_out,
+        'Log Out Everywhere': log_out_everywhere,
     };
 
     if (USER_DATA.secret.server_admin) {
-        user_actions['Shutdown Server'] = shutdown_server;
+        user_actions['Admin'] = {
+            'Server Stats': show_server_stats,
+            'Manage User': manage_user,
+            'Issue Reset Code': issue_reset_code,
+            'Reset Two-Factor': reset_two_factor,
+            'Force Backup': force_backup,
+            'Shutdown Server': shutdown_server,
+        };
     }
 
     init_context_menu(find('settings-btn'), user_actions);
@@ -66,33 +156,32 @@ async function login_auth() {
     find('password-input').value = '';
 }
 
-async function login_token() {
+async function login_token(code) {
+    let username = find('username-input').value;
     let password = find('password-input').value;
 
     try {
-        let parameters = [USER_ID, password];
-        let [_, token] = await request('get-token', parameters);
+        let parameters = [username, password, device_label(), code || null];
+        let [_, [user_id, token]] = await request('get-token', parameters);
+        USER_ID = user_id;
         localStorage['user-id'] = USER_ID;
         localStorage['token-' + USER_ID] = token;
-    } catch {
-        alert("Invalid Password!");
+    } catch (e) {
+        if (e === 'Two-factor code required') {
+            let code = prompt('Code from your authenticator app (or a recovery code):');
+            if (code) await login_token(code);
+        } else if (e !== 'Wrong password') {
+            alert(e);
+        } else if (confirm('Invalid Password! Reset it?')) {
+            await reset_password();
+        }
+
         return;
     }
 
     await login_auth();
 }
 
-async function check_username() {
-    try {
-        let username = find('username-input').value;
-        let [_, user_id] = await request('who-is', username);
-        USER_ID = user_id;
-        await login_token();
-    } catch (e) {
-        alert(e);
-    }
-}
-
 async function create_account() {
     let username = find('username-input').value;
     let password = find('password-input').value;
@@ -115,11 +204,9 @@ async function create_account() {
 
     try {
         let parameters = [username, password];
-        let [_, user_id] = await request('create-account', parameters);
-        USER_ID = user_id;
-        localStorage['user-id'] = user_id;
-    } catch {
-        alert("Username already taken!");
+        let _ = await request('create-account', parameters);
+    } catch (e) {
+        alert(e === 'Username already taken' ? 'Username already taken!' : e);
         return;
     }
 
@@ -128,7 +215,7 @@ async function create_account() {
 
 async function login_or_create_account() {
     let login = find('login-button').classList.contains('selected');
-    await login ? check_username() : create_account();
+    await login ? login_token() : create_account();
 }
 
 async function send_friend_request() {
@@ -200,11 +287,177 @@ async function user_click() {
     }
 }
 
-async function log_out() {
+function prompt_new_password() {
+    let password = prompt('New password:');
+    if (!password) return null;
+
+    if (password.length < 4) {
+        alert('Password must be at least 4 characters long');
+        return null;
+    }
+
+    if (prompt('Confirm the new password:') !== password) {
+        alert('Passwords differ');
+        return null;
+    }
+
+    return password;
+}
+
+async function change_password() {
+    let old_password = prompt('Current password:');
+    if (!old_password) return;
+    let new_password = prompt_new_password();
+    if (!new_password) return;
+
+    try {
+        let _ = await request('change-password', [old_password, new_password]);
+        alert('Password changed; other devices were logged out.');
+    } catch (e) {
+        alert(e);
+    }
+}
+
+// with a code from an admin, or else by email
+async function reset_password() {
+    let code = prompt('Reset code from an admin (leave empty to receive one by email):');
+    if (code === null) return;
+
+    try {
+        if (code) {
+            let new_password = prompt_new_password();
+            if (!new_password) return;
+            let username = find('username-input').value;
+            let _ = await request('redeem-reset-code', [username, code, new_password]);
+        } else {
+            let email = prompt('Email address of your account:');
+            if (!email) return;
+            let _a = await request('send-challenge', [email, 'PasswordReset']);
+            let email_code = prompt('Code received by email:');
+            if (!email_code) return;
+            let _b = await request('complete-challenge', email_code);
+            let new_password = prompt_new_password();
+            if (!new_password) return;
+            let _c = await request('reset-password', new_password);
+        }
+
+        alert('Password reset; you can now log in.');
+    } catch (e) {
+        alert(e);
+    }
+}
+
+async function issue_reset_code() {
+    let username = prompt('Username:');
+    if (!username) return;
+
+    try {
+        let [_a, user_id] = await request('who-is', username);
+        let [_b, code] = await request('issue-reset-code', user_id);
+        prompt('One-time reset code for ' + username + ' (valid for a day):', code);
+    } catch (e) {
+        alert(e);
+    }
+}
+
+function show_recovery_codes(codes) {
+    let text = 'Recovery codes, each usable once if you lose your authenticator:';
+    prompt(text, codes.join(' '));
+}
+
+async function manage_two_factor() {
+    try {
+        if (USER_DATA.secret.two_factor) {
+            if (confirm('Generate new recovery codes? (Cancel to disable two-factor authentication)')) {
+                let code = prompt('Code from your authenticator app:');
+                if (!code) return;
+                let [_, codes] = await request('new-recovery-codes', code);
+                show_recovery_codes(codes);
+            } else {
+                let password = prompt('Password:');
+                if (password === null) return;
+                let code = prompt('Code from your authenticator app (or a recovery code):');
+                if (!code) return;
+                let _ = await request('disable-two-factor', [password, code]);
+                alert('Two-factor authentication disabled.');
+            }
+        } else {
+            let [_a, [secret, uri]] = await request('setup-two-factor', null);
+            prompt('Add this account to your authenticator app (secret: ' + secret + '):', uri);
+            let code = prompt('Code shown by your authenticator app:');
+            if (!code) return;
+            let [_b, codes] = await request('enable-two-factor', code);
+            show_recovery_codes(codes);
+        }
+    } catch (e) {
+        alert(e);
+    }
+
+    await load_user_data();
+}
+
+async function reset_two_factor() {
+    let username = prompt('Username:');
+    if (!username) return;
+
+    try {
+        let [_a, user_id] = await request('who-is', username);
+        let _b = await request('reset-two-factor', user_id);
+        alert('Two-factor authentication disabled for ' + username + '.');
+    } catch (e) {
+        alert(e);
+    }
+}
+
+// shown in the list of devices, e.g. 'Firefox on Linux'
+function device_label() {
+    let agent = navigator.userAgent;
+    let browsers = ['Edg', 'Firefox', 'Chrome', 'Safari'];
+    let systems = ['Android', 'iPhone', 'Windows', 'Mac OS', 'Linux'];
+    let browser = browsers.find(name => agent.includes(name)) || 'Browser';
+    let system = systems.find(name => agent.includes(name)) || 'unknown OS';
+    if (browser == 'Edg') browser = 'Edge';
+    return browser + ' on ' + system;
+}
+
+function forget_token() {
+    delete localStorage['token-' + USER_ID];
     delete localStorage['user-id'];
+}
+
+async function log_out() {
+    try {
+        await request('revoke-token', null);
+    } catch (e) {
+        console.warn(e);
+    }
+
+    forget_token();
     location.reload();
 }
 
+async function log_out_everywhere() {
+    if (!confirm('Log out on every other device?')) return;
+    let _ = await request('revoke-other-tokens', null);
+    alert('Other devices were logged out.');
+}
+
+async function show_devices() {
+    let [_, tokens] = await request('list-tokens', null);
+    let lines = tokens.map((token, i) => {
+        let line = (i + 1) + '. ' + token.label;
+        line += ' (last used ' + datetime_string(token.last_used) + ')';
+        return line + (token.current ? ' - this device' : '');
+    });
+
+    let text = lines.join('\n') + '\n\nLog out a device? Enter its number:';
+    let choice = parseInt(prompt(text));
+    let token = tokens[choice - 1];
+    if (token === undefined) return;
+
+    let _r = await request('revoke-token', token.id);
+}
+
 function find_conversation(user_id) {
     console.log(user_id);
     let entity_ids = Object.keys(USER_DATA.secret.entities);
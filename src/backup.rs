@@ -1,18 +1,29 @@
 use litemap::LiteMap;
 use async_io::Timer;
+use async_fs::{read_to_string, rename, metadata, File};
+use futures_lite::AsyncWriteExt;
+use blocking::unblock;
 
-use crate::{DATABASE, FULL_DB_ACCESS, Receiver, or};
-use crate::database::objects::Hash;
+use crate::{DATABASE, FULL_DB_ACCESS, Receiver, StringifyError, or};
+use crate::database::{Database, objects::Hash};
+use crate::database::journal;
+use crate::config::{config, SNAPSHOT, SNAPSHOT_NEW, SNAPSHOT_OLD, JOURNAL_OLD};
 
-use std::fs::{write, rename, remove_file};
+use std::fs::remove_file;
+use std::sync::atomic::Ordering;
 use std::time::{Instant, Duration};
 use std::process::exit;
 use std::mem::take;
 
-const BACKUP_PERIOD_MINUTES: u64 = 20;
+pub enum BackupSignal {
+    /// Writes a snapshot, then exits
+    Exit,
+    /// Writes a snapshot without waiting for the next period
+    Now,
+}
 
 fn try_remove_file(hash: &Hash) {
-    let path = format!("files/{}.dat", hash);
+    let path = config().file_path(hash);
     if let Err(error) = remove_file(path) {
         println!("FGC: Failed to delete file {}: {:?}", hash, error);
     } else {
@@ -20,24 +31,49 @@ fn try_remove_file(hash: &Hash) {
     }
 }
 
-pub async fn backup_task(rx_signal: Receiver<()>) {
-    loop {
-        let timeout = async {
-            Timer::after(Duration::from_secs(60 * BACKUP_PERIOD_MINUTES)).await;
-            false
-        };
+/// Replays the rotated journal over the current snapshot, in a
+/// database of its own, and serializes the result
+async fn compact() -> Result<String, String> {
+    let db_json = read_to_string(config().database_path(SNAPSHOT)).await.fmt_err("read")?;
+    let db = Database::init();
+    let snapshot_seq = db.load_from_json(&db_json).await;
+    drop(db_json);
 
-        let recv_save_signal = async {
-            rx_signal.recv().await.unwrap();
-            true
-        };
+    let old_journal = config().database_path(JOURNAL_OLD);
+    let journal_seq = journal::replay_file(&db, &old_journal, snapshot_seq).await;
+    db.journal_seq.store(journal_seq, Ordering::SeqCst);
 
-        let must_exit = or(timeout, recv_save_signal).await;
+    // collected files
+    db.file_rc.write().await.retain(|_, counter| *counter > 0);
 
-        let then = Instant::now();
-        println!("scheduled database backup");
+    unblock(move || serde_json::to_string(&db)).await.fmt_err("serialize")
+}
+
+/// Replaces database.json, keeping the previous one as a fallback
+async fn write_snapshot(json_dump: String) -> Result<(), String> {
+    let path = |name| config().database_path(name);
+
+    let mut file = File::create(path(SNAPSHOT_NEW)).await.fmt_err("create")?;
+    file.write_all(json_dump.as_bytes()).await.fmt_err("write")?;
+    file.sync_all().await.fmt_err("sync")?;
+    drop(file);
+
+    if metadata(path(SNAPSHOT)).await.is_ok() {
+        rename(path(SNAPSHOT), path(SNAPSHOT_OLD)).await.fmt_err("rename old")?;
+    }
+
+    rename(path(SNAPSHOT_NEW), path(SNAPSHOT)).await.fmt_err("rename new")
+}
+
+/// Compacts the journal into a new database.json snapshot
+async fn snapshot() {
+    let then = Instant::now();
+
+    // the database is only frozen while files are collected and the
+    // journal is rotated; the snapshot is built from the files
+    if true {
         let _writer = FULL_DB_ACCESS.write().await;
-        println!("beginning database backup");
+        println!("beginning database snapshot");
 
         // file garbage collection
         if true {
@@ -54,14 +90,46 @@ pub async fn backup_task(rx_signal: Receiver<()>) {
             *file_rc = LiteMap::from_sorted_store_unchecked(filtered);
         }
 
-        rename("database.json", "database-old.json").unwrap();
-        let json_dump = serde_json::to_string_pretty(&DATABASE).unwrap();
-        write("database.json", json_dump.as_bytes()).unwrap();
+        journal::rotate().await;
+    }
+
+    let frozen = then.elapsed().as_millis();
 
-        let duration = then.elapsed().as_millis();
-        println!("database backed up in {}ms", duration);
-        if must_exit {
+    let json_dump = match compact().await {
+        Ok(json_dump) => json_dump,
+        // the rotated journal is kept, and compacted next time
+        Err(msg) => return println!("database snapshot failed: {}", msg),
+    };
+
+    if let Err(msg) = write_snapshot(json_dump).await {
+        return println!("database snapshot failed: {}", msg);
+    }
+
+    // entries of the rotated journal are in the snapshot now
+    journal::discard_rotated().await;
+
+    let duration = then.elapsed().as_millis();
+    println!("database snapshot written in {}ms (frozen for {}ms)", duration, frozen);
+}
+
+pub async fn backup_task(rx_signal: Receiver<BackupSignal>) {
+    loop {
+        let timeout = async {
+            Timer::after(Duration::from_secs(60 * config().backup_period)).await;
+            None
+        };
+
+        let recv_save_signal = async {
+            Some(rx_signal.recv().await.unwrap())
+        };
+
+        let signal = or(timeout, recv_save_signal).await;
+
+        println!("scheduled database backup");
+        snapshot().await;
+
+        if let Some(BackupSignal::Exit) = signal {
             exit(0);
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,197 @@
+use serde::{Serialize, Deserialize};
+
+use std::sync::OnceLock;
+use std::fs::read_to_string;
+use std::str::FromStr;
+use std::env::var;
+use std::thread::available_parallelism;
+
+const DEFAULT_CONFIG_PATH: &str = "kolab.json";
+
+/// Files of the database, in `database_dir`
+pub const SNAPSHOT: &str = "database.json";
+pub const SNAPSHOT_NEW: &str = "database-new.json";
+pub const SNAPSHOT_OLD: &str = "database-old.json";
+pub const JOURNAL: &str = "journal.jsonl";
+pub const JOURNAL_OLD: &str = "journal-old.jsonl";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Server settings, read from `kolab.json` (or the file named
+/// by KOLAB_CONFIG) then overridden by environment variables.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// KOLAB_ADDR
+    pub addr: String,
+    /// KOLAB_BACKUP_PERIOD, in minutes
+    pub backup_period: u64,
+    /// KOLAB_MAX_FILE_SIZE, in bytes, for new accounts
+    pub max_file_size: usize,
+    /// KOLAB_STORAGE_QUOTA, in bytes, for each account
+    pub storage_quota: usize,
+    /// KOLAB_MESSAGES_PER_PAGE
+    pub messages_per_page: usize,
+    /// KOLAB_DATABASE_DIR, for the snapshot and the journal
+    pub database_dir: String,
+    /// KOLAB_FILES_DIR
+    pub files_dir: String,
+    /// KOLAB_FRONT_DIR
+    pub front_dir: String,
+    /// KOLAB_THREADS, including the main thread; 0 follows the CPU count
+    pub threads: usize,
+    /// KOLAB_TLS_CERT, PEM certificate chain; enables HTTPS
+    pub tls_cert: Option<String>,
+    /// KOLAB_TLS_KEY, PEM private key
+    pub tls_key: Option<String>,
+    /// KOLAB_REDIRECT_ADDR, plain HTTP listener redirecting to HTTPS
+    pub redirect_addr: Option<String>,
+    /// KOLAB_TOKEN_LIFETIME_DAYS, since the last use of a token
+    pub token_lifetime_days: u64,
+    /// KOLAB_WATCH_FRONT, dev mode: rebuild assets when they change
+    pub watch_front: bool,
+    /// KOLAB_RELOAD_CLIENTS, with `watch_front`: tell clients to reload
+    pub reload_clients: bool,
+    pub mail: MailConfig,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MailConfig {
+    /// KOLAB_SMTP_SERVER; mails are written to `file` if unset
+    pub smtp_server: Option<String>,
+    /// KOLAB_MAIL_FROM
+    pub from: String,
+    /// KOLAB_SMTP_USER
+    pub smtp_user: Option<String>,
+    /// KOLAB_SMTP_PASSWORD
+    pub smtp_password: Option<String>,
+    /// KOLAB_SMTP_IMPLICIT_TLS; connections are upgraded with STARTTLS otherwise
+    pub smtp_implicit_tls: bool,
+    /// KOLAB_SMTP_CA_FILE; PEM bundle of trusted root certificates,
+    /// the system's bundle if unset
+    pub smtp_ca_file: Option<String>,
+    /// KOLAB_MAIL_FILE; mails are printed if unset
+    pub file: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addr: "0.0.0.0:8080".into(),
+            backup_period: 20,
+            max_file_size: 50 * 1024 * 1024, // 50 MiB
+            storage_quota: 1024 * 1024 * 1024, // 1 GiB
+            messages_per_page: 50,
+            database_dir: ".".into(),
+            files_dir: "files".into(),
+            front_dir: "front".into(),
+            threads: 0,
+            tls_cert: None,
+            tls_key: None,
+            redirect_addr: None,
+            token_lifetime_days: 30,
+            watch_front: false,
+            reload_clients: false,
+            mail: MailConfig {
+                from: "kolab@localhost".into(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl Config {
+    /// Path of a stored file, `name` being a hash or a temporary name
+    pub fn file_path(&self, name: &str) -> String {
+        format!("{}/{}.dat", self.files_dir, name)
+    }
+
+    /// Path of a database file, `SNAPSHOT` or `JOURNAL` for instance
+    pub fn database_path(&self, name: &str) -> String {
+        format!("{}/{}", self.database_dir, name)
+    }
+
+    pub fn front_path(&self, path: &str) -> String {
+        format!("{}/{}", self.front_dir, path)
+    }
+
+    /// In seconds
+    pub fn token_lifetime(&self) -> u64 {
+        self.token_lifetime_days * 24 * 60 * 60
+    }
+
+    fn apply_env(&mut self) {
+        env_override(&mut self.addr, "KOLAB_ADDR");
+        env_override(&mut self.backup_period, "KOLAB_BACKUP_PERIOD");
+        env_override(&mut self.max_file_size, "KOLAB_MAX_FILE_SIZE");
+        env_override(&mut self.storage_quota, "KOLAB_STORAGE_QUOTA");
+        env_override(&mut self.messages_per_page, "KOLAB_MESSAGES_PER_PAGE");
+        env_override(&mut self.database_dir, "KOLAB_DATABASE_DIR");
+        env_override(&mut self.files_dir, "KOLAB_FILES_DIR");
+        env_override(&mut self.front_dir, "KOLAB_FRONT_DIR");
+        env_override(&mut self.threads, "KOLAB_THREADS");
+        env_override_opt(&mut self.tls_cert, "KOLAB_TLS_CERT");
+        env_override_opt(&mut self.tls_key, "KOLAB_TLS_KEY");
+        env_override_opt(&mut self.redirect_addr, "KOLAB_REDIRECT_ADDR");
+        env_override(&mut self.token_lifetime_days, "KOLAB_TOKEN_LIFETIME_DAYS");
+        env_override(&mut self.watch_front, "KOLAB_WATCH_FRONT");
+        env_override(&mut self.reload_clients, "KOLAB_RELOAD_CLIENTS");
+
+        let mail = &mut self.mail;
+        env_override_opt(&mut mail.smtp_server, "KOLAB_SMTP_SERVER");
+        env_override(&mut mail.from, "KOLAB_MAIL_FROM");
+        env_override_opt(&mut mail.smtp_user, "KOLAB_SMTP_USER");
+        env_override_opt(&mut mail.smtp_password, "KOLAB_SMTP_PASSWORD");
+        env_override(&mut mail.smtp_implicit_tls, "KOLAB_SMTP_IMPLICIT_TLS");
+        env_override_opt(&mut mail.smtp_ca_file, "KOLAB_SMTP_CA_FILE");
+        env_override_opt(&mut mail.file, "KOLAB_MAIL_FILE");
+    }
+}
+
+fn parse_env<T: FromStr>(name: &str) -> Option<T> {
+    let value = var(name).ok()?;
+    let parsed = value.parse().ok();
+
+    if parsed.is_none() {
+        println!("config: ignoring invalid {}", name);
+    }
+
+    parsed
+}
+
+fn env_override<T: FromStr>(field: &mut T, name: &str) {
+    if let Some(value) = parse_env(name) {
+        *field = value;
+    }
+}
+
+fn env_override_opt<T: FromStr>(field: &mut Option<T>, name: &str) {
+    if let Some(value) = parse_env(name) {
+        *field = Some(value);
+    }
+}
+
+/// Loads the configuration; must be called once, before `config()`
+pub fn init_config() {
+    let path = var("KOLAB_CONFIG").unwrap_or(DEFAULT_CONFIG_PATH.into());
+
+    let mut config: Config = match read_to_string(&path) {
+        Ok(json) => serde_json::from_str(&json).expect("invalid configuration file"),
+        Err(_) => {
+            println!("config: {} not found, using defaults", path);
+            Config::default()
+        },
+    };
+
+    config.apply_env();
+    if config.threads == 0 {
+        config.threads = available_parallelism().map_or(1, |n| n.get());
+    }
+
+    let _ = CONFIG.set(config);
+}
+
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("configuration not loaded")
+}
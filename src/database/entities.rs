@@ -1,8 +1,12 @@
 use serde::{Serialize, Deserialize};
 
 use crate::serde_utils::SerdeRwLock as RwLock;
-use super::objects::{UserId, AssociatedImage};
+use super::EntityId;
+use super::objects::{UserId, AssociatedImage, Stamp, now_stamp};
+use super::journal::{self, JournalEntry, EntityKind};
+use super::update::{Update, UpdateType};
 
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
@@ -10,6 +14,13 @@ use std::ops::{Deref, DerefMut};
 pub type EntityTag = String;
 pub type Revision = u32;
 pub type IndexInEntity = u64;
+/// Update to commit after a metadata change, with the concerned user as data
+pub type MetadataChange = (UpdateType, UserId);
+
+/// Number of recent updates kept in memory for catch-up sync
+const RECENT_UPDATES: usize = 256;
+/// Number of history records kept per entity, older ones are dropped
+const HISTORY_LENGTH: usize = 1024;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(transparent)] 
@@ -21,6 +32,12 @@ pub struct Entities<T: Debug> {
 pub struct Entity<T: Debug> {
     pub inner: T,
     pub metadata: EntityData,
+    /// Latest changes, ordered by revision
+    #[serde(default)]
+    pub history: VecDeque<(Revision, Change)>,
+    /// Latest updates, ordered by revision
+    #[serde(skip)]
+    pub recent: VecDeque<Update>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -29,6 +46,23 @@ pub struct EntityData {
     pub author: UserId,
     pub guests: Vec<UserId>,
     pub revision: Revision,
+    #[serde(default)]
+    pub banned: Vec<UserId>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub author: UserId,
+    pub stamp: Stamp,
+    #[serde(rename = "type")]
+    pub change_type: UpdateType,
+    pub index: IndexInEntity,
+    /// Same as the data of the update
+    #[serde(default)]
+    pub data: serde_json::Value,
+    /// Caused by another change of the same revision
+    #[serde(default)]
+    pub derived: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,6 +73,158 @@ pub struct EntityAccess {
     pub last_seen_rev: Revision,
 }
 
+impl EntityData {
+    /// Makes `guest` the author; the current author becomes a guest
+    pub fn transfer(&mut self, author: UserId, guest: UserId) -> Result<Vec<MetadataChange>, &'static str> {
+        if self.author != author {
+            return Err("User is not the author of this entity");
+        }
+
+        let i = self.guests.iter().position(|g| *g == guest).ok_or("Not a guest")?;
+        self.guests[i] = author;
+        self.author = guest;
+        Ok(vec![(UpdateType::NewOwner, guest)])
+    }
+
+    /// `invited` tells if `user` has a pending invite to this entity,
+    /// in which case it's banned before joining.
+    pub fn ban(&mut self, author: UserId, user: UserId, invited: bool) -> Result<Vec<MetadataChange>, &'static str> {
+        if self.author != author {
+            return Err("User is not the author of this entity");
+        }
+
+        match self.guests.iter().position(|g| *g == user) {
+            Some(i) => {
+                self.guests.remove(i);
+                self.banned.push(user);
+                Ok(vec![(UpdateType::ByeGuest, user)])
+            },
+            None if invited && !self.banned.contains(&user) => {
+                self.banned.push(user);
+                Ok(Vec::new())
+            },
+            None => Err("Neither a guest nor invited"),
+        }
+    }
+
+    pub fn unban(&mut self, author: UserId, user: UserId) -> Result<(), &'static str> {
+        if self.author != author {
+            return Err("User is not the author of this entity");
+        }
+
+        let i = self.banned.iter().position(|b| *b == user).ok_or("Not banned")?;
+        self.banned.remove(i);
+        Ok(())
+    }
+}
+
+impl<T: Debug + Default> Entity<T> {
+    fn new(metadata: EntityData) -> Self {
+        Self {
+            inner: T::default(),
+            metadata,
+            history: VecDeque::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Records a committed update in the history of this entity
+    pub fn commit(&mut self, author: UserId, update: &Update) {
+        self.commit_change(author, update, false);
+    }
+
+    /// Commits an update which derives from another one
+    /// made by `author` (e.g. recomputed cells)
+    pub fn commit_derived(&mut self, author: UserId, update: &Update) {
+        self.commit_change(author, update, true);
+    }
+
+    fn commit_change(&mut self, author: UserId, update: &Update, derived: bool) {
+        let change = Change {
+            author,
+            stamp: now_stamp(),
+            change_type: update.update_type,
+            index: update.index,
+            data: update.data.clone(),
+            derived,
+        };
+
+        self.record(update.new_revision, change.clone());
+        self.remember(update);
+        journal::append(JournalEntry::Commit(Some(change), update.clone()));
+    }
+
+    /// Commits metadata changes in a new revision; the
+    /// first one is made by `author`, the others derive from it.
+    fn commit_metadata(&mut self, id: EntityId, author: UserId, changes: Vec<MetadataChange>) -> Vec<Update> {
+        if changes.is_empty() {
+            return Vec::new();
+        }
+
+        self.metadata.revision += 1;
+        let rev = self.metadata.revision;
+        let updates: Vec<_> = changes.into_iter().map(|(update_type, user)| {
+            Update::new(update_type, id, rev, 0, &user)
+        }).collect();
+
+        self.commit(author, &updates[0]);
+        for update in &updates[1..] {
+            self.commit_derived(author, update);
+        }
+
+        updates
+    }
+
+    fn record(&mut self, revision: Revision, change: Change) {
+        self.history.push_back((revision, change));
+
+        // older snapshots may hold more
+        let excess = self.history.len().saturating_sub(HISTORY_LENGTH);
+        self.history.drain(..excess);
+    }
+
+    /// Keeps an update around so that clients can catch up later
+    fn remember(&mut self, update: &Update) {
+        self.recent.push_back(update.clone());
+
+        if self.recent.len() > RECENT_UPDATES {
+            // evict whole revisions only
+            let oldest = self.recent.pop_front().unwrap().new_revision;
+            while self.recent.front().is_some_and(|u| u.new_revision == oldest) {
+                self.recent.pop_front();
+            }
+        }
+    }
+
+    /// Forgets everything about the content of this entity
+    fn reset(&mut self) {
+        self.inner = Default::default();
+        self.history.clear();
+        self.recent.clear();
+        self.metadata.author = UserId::MAX;
+    }
+
+    /// Returns the current revision and the updates which a client at
+    /// `revision` has missed, or None unless all of them are remembered.
+    pub fn updates_since(&self, revision: Revision) -> Option<(Revision, Vec<Update>)> {
+        let current = self.metadata.revision;
+        let missed: Vec<_> = self.recent.iter().filter(|u| u.new_revision > revision).cloned().collect();
+
+        // each revision up to the current one, in order
+        let mut next = revision + 1;
+        for update in &missed {
+            match update.new_revision {
+                rev if rev == next => next += 1,
+                // more updates in the same revision
+                rev if rev + 1 == next => (),
+                _ => return None,
+            }
+        }
+
+        (revision <= current && next == current + 1).then_some((current, missed))
+    }
+}
+
 impl<T: Debug> Deref for Entity<T> {
     type Target = T;
 
@@ -53,7 +239,7 @@ impl<T: Debug> DerefMut for Entity<T> {
     }
 }
 
-impl<T: Debug + Default> Entities<T> {
+impl<T: EntityKind> Entities<T> {
     pub const fn init() -> Self {
         Self {
             inner: RwLock::new(Vec::new()),
@@ -65,51 +251,115 @@ impl<T: Debug + Default> Entities<T> {
         reader.get(raw_id as usize).cloned()
     }
 
+    /// Number of entities, including abandoned ones
+    pub async fn count(&self) -> u32 {
+        self.inner.read().await.len() as u32
+    }
+
     pub async fn metadata(&self, raw_id: u32) -> Option<EntityData> {
         let arc_user = self.find(raw_id).await?;
         let user = arc_user.read().await;
         Some(user.metadata.clone())
     }
 
+    /// Up to `limit` changes made after revision `since`, oldest first;
+    /// the last revision is never split, so pages can resume after it.
+    pub async fn history(&self, raw_id: u32, since: Revision, limit: usize) -> Option<Vec<(Revision, Change)>> {
+        let arc_entity = self.find(raw_id).await?;
+        let entity = arc_entity.read().await;
+
+        let start = entity.history.partition_point(|(rev, _)| *rev <= since);
+        let mut records = entity.history.range(start..);
+        let mut page: Vec<_> = records.by_ref().take(limit).cloned().collect();
+
+        if let Some(last) = page.last().map(|(rev, _)| *rev) {
+            page.extend(records.take_while(|(rev, _)| *rev == last).cloned());
+        }
+
+        Some(page)
+    }
+
+    pub async fn updates_since(
+        &self,
+        raw_id: u32,
+        revision: Revision,
+    ) -> Option<(Revision, Vec<Update>)> {
+        let arc_entity = self.find(raw_id).await?;
+        let entity = arc_entity.read().await;
+        entity.updates_since(revision)
+    }
+
+    pub async fn update_metadata<F>(&self, raw_id: u32, f: F) -> Result<(), &'static str>
+    where
+        F: FnOnce(&mut EntityData) -> Result<(), &'static str>,
+    {
+        let arc_entity = self.find(raw_id).await.ok_or("No such entity")?;
+        let mut entity = arc_entity.write().await;
+        f(&mut entity.metadata)?;
+
+        journal::append(JournalEntry::Metadata(T::entity_id(raw_id), entity.metadata.clone()));
+        Ok(())
+    }
+
+    /// Changes the metadata with `f`, then commits the updates
+    /// it returns so that clients can sync them
+    pub async fn commit_metadata<F>(&self, raw_id: u32, author: UserId, f: F) -> Result<Vec<Update>, &'static str>
+    where
+        F: FnOnce(&mut EntityData) -> Result<Vec<MetadataChange>, &'static str>,
+    {
+        let arc_entity = self.find(raw_id).await.ok_or("No such entity")?;
+        let mut entity = arc_entity.write().await;
+        let changes = f(&mut entity.metadata)?;
+
+        journal::append(JournalEntry::Metadata(T::entity_id(raw_id), entity.metadata.clone()));
+        Ok(entity.commit_metadata(T::entity_id(raw_id), author, changes))
+    }
+
     pub async fn new_entity(&self, metadata: EntityData) -> u32 {
-        let entity = Entity {
-            inner: T::default(),
-            metadata,
-        };
+        let entity = Entity::new(metadata);
 
-        let arc_entity = Arc::new(RwLock::new(entity));
         let mut writer = self.inner.write().await;
         let raw_id = writer.len() as u32;
-        writer.push(arc_entity);
+        let entry = JournalEntry::NewEntity(T::entity_id(raw_id), entity.metadata.clone());
+        journal::append(entry);
 
+        writer.push(Arc::new(RwLock::new(entity)));
         raw_id
     }
 
-    pub(super) async fn try_push_guest(&self, raw_id: u32, guest: UserId) {
+    pub(super) async fn try_push_guest(&self, raw_id: u32, guest: UserId) -> Vec<Update> {
         let Some(arc_entity) = self.find(raw_id).await else {
             println!("couldn't push guest!!");
-            return;
+            return Vec::new();
         };
 
         let mut entity = arc_entity.write().await;
         entity.metadata.guests.push(guest);
+        journal::append(JournalEntry::Metadata(T::entity_id(raw_id), entity.metadata.clone()));
+
+        let changes = vec![(UpdateType::NewGuest, guest)];
+        entity.commit_metadata(T::entity_id(raw_id), guest, changes)
     }
 
-    pub(super) async fn try_drop_access(&self, raw_id: u32, user_id: UserId) {
+    pub(super) async fn try_drop_access(&self, raw_id: u32, user_id: UserId) -> Vec<Update> {
         let Some(arc_entity) = self.find(raw_id).await else {
             println!("couldn't remove user access!!");
-            return;
+            return Vec::new();
         };
 
         let mut entity = arc_entity.write().await;
+        let mut changes = vec![(UpdateType::ByeGuest, user_id)];
+
         if entity.metadata.author == user_id {
             if entity.metadata.guests.is_empty() {
-                entity.inner = Default::default();
-                entity.metadata.author = UserId::MAX;
+                // nobody left to notify
+                entity.reset();
+                changes.clear();
             } else {
                 // make oldest guest the owner (author)
                 let new_author = entity.metadata.guests.swap_remove(0);
                 entity.metadata.author = new_author;
+                changes.push((UpdateType::NewOwner, new_author));
             }
         } else {
             let guests = &mut entity.metadata.guests;
@@ -117,8 +367,12 @@ impl<T: Debug + Default> Entities<T> {
                 guests.remove(i);
             } else {
                 println!("No such guest");
+                changes.clear();
             }
         }
+
+        journal::append(JournalEntry::Metadata(T::entity_id(raw_id), entity.metadata.clone()));
+        entity.commit_metadata(T::entity_id(raw_id), user_id, changes)
     }
 
     pub(super) async fn restore(&self, reference: Self) {
@@ -126,4 +380,196 @@ impl<T: Debug + Default> Entities<T> {
         let mut dst = self.inner.write().await;
         *dst = std::mem::take(&mut src);
     }
+
+    async fn find_replayed(&self, raw_id: u32) -> Result<Arc<RwLock<Entity<T>>>, &'static str> {
+        self.find(raw_id).await.ok_or("no such entity")
+    }
+
+    pub(super) async fn replay(
+        &self,
+        raw_id: u32,
+        change: Option<Change>,
+        update: &Update,
+    ) -> Result<(), &'static str> {
+        let arc_entity = self.find_replayed(raw_id).await?;
+        let mut entity = arc_entity.write().await;
+        // metadata itself is journaled separately
+        if !update.update_type.is_metadata() {
+            entity.inner.apply(update)?;
+        }
+
+        entity.metadata.revision = update.new_revision;
+
+        if let Some(change) = change {
+            entity.record(update.new_revision, change);
+        }
+
+        entity.remember(update);
+        Ok(())
+    }
+
+    pub(super) async fn replay_new(&self, raw_id: u32, metadata: EntityData) -> Result<(), &'static str> {
+        let mut writer = self.inner.write().await;
+        let raw_id = raw_id as usize;
+
+        while writer.len() <= raw_id {
+            let mut placeholder = Entity::new(metadata.clone());
+            placeholder.metadata.author = UserId::MAX;
+            writer.push(Arc::new(RwLock::new(placeholder)));
+        }
+
+        writer[raw_id] = Arc::new(RwLock::new(Entity::new(metadata)));
+        Ok(())
+    }
+
+    pub(super) async fn replay_metadata(
+        &self,
+        raw_id: u32,
+        metadata: EntityData,
+    ) -> Result<(), &'static str> {
+        let arc_entity = self.find_replayed(raw_id).await?;
+        let mut entity = arc_entity.write().await;
+        let revision = entity.metadata.revision;
+        entity.metadata = EntityData { revision, ..metadata };
+
+        if entity.metadata.author == UserId::MAX {
+            entity.reset();
+        }
+
+        Ok(())
+    }
+
+    pub(super) async fn replay_inner<F: FnOnce(&mut T)>(&self, raw_id: u32, f: F) -> Result<(), &'static str> {
+        let arc_entity = self.find_replayed(raw_id).await?;
+        f(&mut arc_entity.write().await.inner);
+        Ok(())
+    }
+
+    pub(super) async fn replay_entity(&self, raw_id: u32, src: Entity<T>) -> Result<(), &'static str> {
+        let arc_entity = self.find_replayed(raw_id).await?;
+        let mut entity = arc_entity.write().await;
+        entity.inner = src.inner;
+        entity.metadata = src.metadata;
+        entity.history = src.history;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::objects::Sheet;
+    use futures_lite::future::block_on;
+
+    fn metadata() -> EntityData {
+        EntityData {
+            image: AssociatedImage::Picture(String::new()),
+            author: 0,
+            guests: Vec::new(),
+            revision: 0,
+            banned: Vec::new(),
+        }
+    }
+
+    fn update(rev: Revision, index: IndexInEntity) -> Update {
+        Update::new(UpdateType::SetCell, Sheet::entity_id(0), rev, index, &())
+    }
+
+    #[test]
+    fn history_is_capped() {
+        let mut sheet = Entity::<Sheet>::new(metadata());
+        for rev in 0..(HISTORY_LENGTH as Revision + 10) {
+            sheet.commit(0, &update(rev, 0));
+        }
+
+        assert_eq!(sheet.history.len(), HISTORY_LENGTH);
+        assert_eq!(sheet.history[0].0, 10);
+    }
+
+    #[test]
+    fn history_pages_keep_revisions_whole() {
+        let sheets = Entities::<Sheet>::init();
+        let raw_id = block_on(sheets.new_entity(metadata()));
+        let arc_sheet = block_on(sheets.find(raw_id)).unwrap();
+
+        block_on(async {
+            let mut sheet = arc_sheet.write().await;
+            for rev in 1..=3 {
+                sheet.commit(0, &update(rev, 0));
+                sheet.commit_derived(0, &update(rev, 1));
+            }
+        });
+
+        let page = block_on(sheets.history(raw_id, 0, 3)).unwrap();
+        let revisions: Vec<_> = page.iter().map(|(rev, _)| *rev).collect();
+        assert_eq!(revisions, [1, 1, 2, 2]);
+        assert!(page[1].1.derived);
+
+        let page = block_on(sheets.history(raw_id, 2, 10)).unwrap();
+        assert_eq!(page.len(), 2);
+        assert!(block_on(sheets.history(raw_id, 3, 10)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn missed_revisions_must_be_contiguous() {
+        let mut sheet = Entity::<Sheet>::new(metadata());
+        for rev in 1..=2 {
+            sheet.metadata.revision = rev;
+            sheet.commit(0, &update(rev, 0));
+            sheet.commit_derived(0, &update(rev, 1));
+        }
+
+        let (current, missed) = sheet.updates_since(0).unwrap();
+        assert_eq!((current, missed.len()), (2, 4));
+        assert!(sheet.updates_since(2).unwrap().1.is_empty());
+        assert!(sheet.updates_since(3).is_none());
+
+        // revision 3 was never committed
+        sheet.metadata.revision = 4;
+        sheet.commit(0, &update(4, 0));
+        assert!(sheet.updates_since(1).is_none());
+        assert_eq!(sheet.updates_since(3).unwrap().1.len(), 1);
+
+        // nothing remembered after a restart
+        sheet.recent.clear();
+        assert!(sheet.updates_since(3).is_none());
+        assert!(sheet.updates_since(4).is_some());
+    }
+
+    #[test]
+    fn dropping_the_author_promotes_a_guest() {
+        let sheets = Entities::<Sheet>::init();
+        let raw_id = block_on(sheets.new_entity(metadata()));
+
+        let updates = block_on(sheets.try_push_guest(raw_id, 1));
+        assert_eq!(updates[0].new_revision, 1);
+
+        let updates = block_on(sheets.try_drop_access(raw_id, 0));
+        let types: Vec<_> = updates.iter().map(|u| (u.update_type, u.new_revision)).collect();
+        assert!(matches!(types[..], [(UpdateType::ByeGuest, 2), (UpdateType::NewOwner, 2)]));
+
+        let metadata = block_on(sheets.metadata(raw_id)).unwrap();
+        assert_eq!((metadata.author, metadata.guests.len()), (1, 0));
+
+        // the updates can be synced
+        let arc_sheet = block_on(sheets.find(raw_id)).unwrap();
+        let (_, missed) = block_on(arc_sheet.read()).updates_since(0).unwrap();
+        assert_eq!(missed.len(), 3);
+
+        // abandoned: nobody to notify
+        assert!(block_on(sheets.try_drop_access(raw_id, 1)).is_empty());
+    }
+
+    #[test]
+    fn invitees_can_be_banned() {
+        let mut metadata = metadata();
+        assert!(metadata.ban(0, 1, false).is_err());
+        assert!(metadata.ban(0, 1, true).unwrap().is_empty());
+        assert_eq!(metadata.banned, [1]);
+
+        metadata.guests.push(2);
+        let changes = metadata.ban(0, 2, false).unwrap();
+        assert!(matches!(changes[..], [(UpdateType::ByeGuest, 2)]));
+        assert!(metadata.guests.is_empty());
+    }
 }
@@ -0,0 +1,535 @@
+use litemap::LiteMap;
+
+use super::entities::IndexInEntity;
+use super::objects::{Sheet, Cell, CellFormula, CellTag};
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, BTreeSet};
+use std::hash::Hash;
+use std::borrow::Borrow;
+
+// error markers, stored in `Cell::value`
+const ERR_CYCLE: &str = "#CYCLE";
+const ERR_REF: &str = "#REF";
+const ERR_VALUE: &str = "#VALUE";
+const ERR_EMPTY: &str = "#EMPTY";
+const ERR_DIV_ZERO: &str = "#DIV/0";
+const ERR_NUM: &str = "#NUM";
+
+#[derive(Debug, Clone)]
+enum Value {
+    Number(f64),
+    Text(String),
+    Error(&'static str),
+}
+
+/// Which cells read which, by index or by tag
+#[derive(Debug, Clone, Default)]
+pub(super) struct Graph {
+    /// formula cells referencing each index
+    by_index: HashMap<IndexInEntity, Vec<IndexInEntity>>,
+    /// formula cells aggregating each tag
+    by_tag: HashMap<CellTag, Vec<IndexInEntity>>,
+    /// cells bearing each tag, in index order
+    tagged: HashMap<CellTag, BTreeSet<IndexInEntity>>,
+}
+
+/// Cells being evaluated, along with their graph
+struct Pass<'a> {
+    cells: &'a LiteMap<IndexInEntity, Cell>,
+    graph: &'a Graph,
+}
+
+impl Sheet {
+    /// Replaces a cell and re-evaluates it along with every formula
+    /// depending on it, directly or not; other cells are left as is.
+    ///
+    /// Returns the indices of other cells whose value changed.
+    pub fn set_cell(&mut self, index: IndexInEntity, cell: Cell) -> Vec<IndexInEntity> {
+        let graph = self.graph.get_or_insert_with(|| Graph::new(&self.cells));
+        let old = self.cells.insert(index, cell);
+        graph.relink(index, old.as_ref(), &self.cells[&index]);
+        let old_tags = old.map(|old| old.tags).unwrap_or_default();
+
+        let pass = Pass { cells: &self.cells, graph };
+        let affected = pass.affected(index, &old_tags);
+        let values = pass.evaluate(&affected);
+
+        let mut changed = Vec::new();
+        for (i, value) in values {
+            let cell = self.cells.get_mut(&i).unwrap();
+            let value = value.to_string();
+            if cell.value != value {
+                cell.value = value;
+                if i != index {
+                    changed.push(i);
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Replaces a cell whose value was computed already, as in replays
+    pub fn restore_cell(&mut self, index: IndexInEntity, cell: Cell) {
+        let old = self.cells.insert(index, cell);
+        if let Some(graph) = &mut self.graph {
+            graph.relink(index, old.as_ref(), &self.cells[&index]);
+        }
+    }
+}
+
+impl Graph {
+    fn new(cells: &LiteMap<IndexInEntity, Cell>) -> Self {
+        let mut graph = Graph::default();
+        for (index, cell) in cells.iter() {
+            graph.link(*index, cell);
+        }
+
+        graph
+    }
+
+    /// Replaces the edges of the cell at `index`
+    fn relink(&mut self, index: IndexInEntity, old: Option<&Cell>, new: &Cell) {
+        if let Some(old) = old {
+            self.unlink(index, old);
+        }
+
+        self.link(index, new);
+    }
+
+    fn link(&mut self, index: IndexInEntity, cell: &Cell) {
+        for tag in &cell.tags {
+            self.tagged.entry(tag.clone()).or_default().insert(index);
+        }
+
+        match cell.formula {
+            CellFormula::Literal => (),
+            formula if formula.uses_tags() => {
+                self.by_tag.entry(cell.text.trim().to_string()).or_default().push(index);
+            },
+            _ => for reference in references(&cell.text).flatten() {
+                self.by_index.entry(reference).or_default().push(index);
+            },
+        }
+    }
+
+    fn unlink(&mut self, index: IndexInEntity, cell: &Cell) {
+        for tag in &cell.tags {
+            if let Some(tagged) = self.tagged.get_mut(tag) {
+                tagged.remove(&index);
+                if tagged.is_empty() {
+                    self.tagged.remove(tag);
+                }
+            }
+        }
+
+        match cell.formula {
+            CellFormula::Literal => (),
+            formula if formula.uses_tags() => unlink_from(&mut self.by_tag, cell.text.trim(), index),
+            _ => for reference in references(&cell.text).flatten() {
+                unlink_from(&mut self.by_index, &reference, index);
+            },
+        }
+    }
+}
+
+fn unlink_from<K, Q>(map: &mut HashMap<K, Vec<IndexInEntity>>, key: &Q, index: IndexInEntity)
+where
+    K: Hash + Eq + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+{
+    if let Some(dependents) = map.get_mut(key) {
+        dependents.retain(|dependent| *dependent != index);
+        if dependents.is_empty() {
+            map.remove(key);
+        }
+    }
+}
+
+impl Pass<'_> {
+    /// Formula cells reading `index`, possibly more than once
+    fn dependents<'b>(&'b self, index: IndexInEntity, tags: &'b [CellTag]) -> impl Iterator<Item = IndexInEntity> + 'b {
+        let by_index = self.graph.by_index.get(&index).into_iter().flatten();
+        let by_tag = tags.iter().filter_map(|tag| self.graph.by_tag.get(tag.as_str())).flatten();
+        by_index.chain(by_tag).copied()
+    }
+
+    /// `edited` and every cell depending on it; `old_tags` are
+    /// the tags `edited` had before the change.
+    fn affected(&self, edited: IndexInEntity, old_tags: &[CellTag]) -> Vec<IndexInEntity> {
+        let mut affected = vec![edited];
+        let mut seen = HashSet::from([edited]);
+        let mut next = 0;
+
+        let mut pending: Vec<_> = self.dependents(edited, old_tags).collect();
+
+        while next < affected.len() {
+            let index = affected[next];
+            next += 1;
+
+            let tags = self.cells.get(&index).map(|c| c.tags.as_slice()).unwrap_or_default();
+            pending.extend(self.dependents(index, tags));
+
+            for dependent in pending.drain(..) {
+                if seen.insert(dependent) {
+                    affected.push(dependent);
+                }
+            }
+        }
+
+        affected
+    }
+
+    /// Cells read by the formula at `index`, in order,
+    /// or an error if its text is malformed.
+    fn inputs(&self, index: IndexInEntity) -> Result<Vec<IndexInEntity>, Value> {
+        let cell = &self.cells[&index];
+        match cell.formula {
+            CellFormula::Literal => Ok(Vec::new()),
+            formula if formula.uses_tags() => {
+                let tagged = self.graph.tagged.get(cell.text.trim()).into_iter().flatten();
+                Ok(tagged.copied().filter(|other| *other != index).collect())
+            },
+            _ => references(&cell.text).collect::<Option<_>>().ok_or(Value::Error(ERR_REF)),
+        }
+    }
+
+    /// Evaluates the `affected` cells, each after its inputs
+    /// (Kahn's algorithm); cells left over are part of a cycle
+    /// or depend on one.
+    fn evaluate(&self, affected: &[IndexInEntity]) -> Vec<(IndexInEntity, Value)> {
+        let members: HashSet<_> = affected.iter().copied().collect();
+        let mut results = HashMap::with_capacity(affected.len());
+        let mut inputs = HashMap::with_capacity(affected.len());
+        let mut waiting: HashMap<_, HashSet<_>> = HashMap::with_capacity(affected.len());
+        let mut ready = Vec::new();
+
+        for index in affected {
+            let cell_inputs = self.inputs(*index);
+            let blockers: HashSet<_> = match &cell_inputs {
+                Ok(list) => list.iter().copied().filter(|i| members.contains(i)).collect(),
+                Err(_) => HashSet::new(),
+            };
+
+            if blockers.is_empty() {
+                ready.push(*index);
+            }
+
+            inputs.insert(*index, cell_inputs);
+            waiting.insert(*index, blockers);
+        }
+
+        while let Some(index) = ready.pop() {
+            let value = match &inputs[&index] {
+                Ok(list) => self.eval(index, list, &results),
+                Err(error) => error.clone(),
+            };
+            results.insert(index, value);
+
+            let cell = &self.cells[&index];
+            for dependent in self.dependents(index, &cell.tags) {
+                let Some(blockers) = waiting.get_mut(&dependent) else {
+                    continue;
+                };
+
+                if blockers.remove(&index) && blockers.is_empty() {
+                    ready.push(dependent);
+                }
+            }
+        }
+
+        affected.iter().map(|index| {
+            let value = results.remove(index).unwrap_or(Value::Error(ERR_CYCLE));
+            (*index, value)
+        }).collect()
+    }
+
+    /// Value of a cell, computed earlier in this pass or stored
+    fn value(&self, index: IndexInEntity, results: &HashMap<IndexInEntity, Value>) -> Value {
+        if let Some(value) = results.get(&index) {
+            return value.clone();
+        }
+
+        match self.cells.get(&index) {
+            None => Value::Error(ERR_REF),
+            Some(cell) if cell.formula == CellFormula::Literal => literal(&cell.text),
+            Some(cell) => stored(&cell.value),
+        }
+    }
+
+    fn eval(&self, index: IndexInEntity, inputs: &[IndexInEntity], results: &HashMap<IndexInEntity, Value>) -> Value {
+        let cell = &self.cells[&index];
+        let mut numbers = Vec::with_capacity(inputs.len());
+
+        match cell.formula {
+            CellFormula::Literal => return literal(&cell.text),
+            // non-numeric inputs are ignored, except by `TagCount`
+            formula if formula.uses_tags() => for input in inputs {
+                match self.value(*input, results) {
+                    Value::Number(number) => numbers.push(number),
+                    Value::Text(_) => (),
+                    error => return error,
+                }
+            },
+            _ => for input in inputs {
+                match self.value(*input, results) {
+                    Value::Number(number) => numbers.push(number),
+                    Value::Text(_) => return Value::Error(ERR_VALUE),
+                    error => return error,
+                }
+            },
+        }
+
+        match cell.formula {
+            CellFormula::TagCount => Value::Number(inputs.len() as f64),
+            formula => formula.apply(&numbers),
+        }
+    }
+}
+
+/// `text` is a list of cell indices, separated by
+/// whitespace, commas or semicolons.
+fn references(text: &str) -> impl Iterator<Item = Option<IndexInEntity>> + '_ {
+    let separators = |c: char| c.is_whitespace() || c == ',' || c == ';';
+    text.split(separators).filter(|s| !s.is_empty()).map(|s| s.parse().ok())
+}
+
+fn literal(text: &str) -> Value {
+    match text.trim().parse() {
+        Ok(number) => Value::Number(number),
+        Err(_) => Value::Text(text.to_string()),
+    }
+}
+
+/// Reads back the value of a formula cell,
+/// which is either a number or an error marker
+fn stored(value: &str) -> Value {
+    const ERRORS: [&str; 6] = [ERR_CYCLE, ERR_REF, ERR_VALUE, ERR_EMPTY, ERR_DIV_ZERO, ERR_NUM];
+
+    match value.parse() {
+        Ok(number) => Value::Number(number),
+        Err(_) => Value::Error(ERRORS.into_iter().find(|e| *e == value).unwrap_or(ERR_VALUE)),
+    }
+}
+
+impl CellFormula {
+    fn uses_tags(self) -> bool {
+        use CellFormula::*;
+        matches!(self, TagSum | TagMean | TagMode | TagCount | TagRange
+            | TagMedian | TagMinimum | TagMaximum | TagProduct)
+    }
+
+    fn apply(self, inputs: &[f64]) -> Value {
+        use CellFormula::*;
+
+        let result = match (self, inputs) {
+            (TagSum | CellsSum, _) => inputs.iter().sum(),
+            (TagProduct | CellsProduct, _) => inputs.iter().product(),
+            (TagCount, _) => inputs.len() as f64,
+            (Literal, _) => return Value::Error(ERR_VALUE),
+            (_, []) => return Value::Error(ERR_EMPTY),
+            (TagMean, _) => inputs.iter().sum::<f64>() / inputs.len() as f64,
+            (TagMinimum, _) => inputs.iter().copied().fold(f64::INFINITY, f64::min),
+            (TagMaximum, _) => inputs.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            (TagRange, _) => {
+                let min = inputs.iter().copied().fold(f64::INFINITY, f64::min);
+                let max = inputs.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                max - min
+            },
+            (TagMedian, _) => {
+                let sorted = sorted(inputs);
+                let mid = sorted.len() / 2;
+                match sorted.len() % 2 {
+                    0 => (sorted[mid - 1] + sorted[mid]) / 2.0,
+                    _ => sorted[mid],
+                }
+            },
+            (TagMode, _) => {
+                // smallest of the most frequent values
+                let sorted = sorted(inputs);
+                let mut best = (sorted[0], 0);
+                for run in sorted.chunk_by(|a, b| a == b) {
+                    if run.len() > best.1 {
+                        best = (run[0], run.len());
+                    }
+                }
+                best.0
+            },
+            (CellsDifference, [first, rest @ ..]) => first - rest.iter().sum::<f64>(),
+            (CellsRatio, [first, rest @ ..]) => {
+                let divisor: f64 = rest.iter().product();
+                if divisor == 0.0 {
+                    return Value::Error(ERR_DIV_ZERO);
+                }
+                first / divisor
+            },
+            (CellsRemainder, [first, rest @ ..]) => {
+                let mut result = *first;
+                for divisor in rest {
+                    if *divisor == 0.0 {
+                        return Value::Error(ERR_DIV_ZERO);
+                    }
+                    result %= divisor;
+                }
+                result
+            },
+            (CellSqrt, [number]) => number.sqrt(),
+            (CellSqrt, _) => return Value::Error(ERR_VALUE),
+        };
+
+        match result.is_finite() {
+            true => Value::Number(result),
+            false => Value::Error(ERR_NUM),
+        }
+    }
+}
+
+fn sorted(inputs: &[f64]) -> Vec<f64> {
+    let mut sorted = inputs.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    sorted
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Text(text) => f.write_str(text),
+            Value::Error(marker) => f.write_str(marker),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(formula: CellFormula, text: &str, tags: &[&str]) -> Cell {
+        Cell {
+            text: text.into(),
+            formula,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            value: String::new(),
+        }
+    }
+
+    fn value(sheet: &Sheet, index: IndexInEntity) -> &str {
+        &sheet.cells[&index].value
+    }
+
+    #[test]
+    fn cell_formulas() {
+        let mut sheet = Sheet::default();
+        sheet.set_cell(0, cell(CellFormula::Literal, "6", &[]));
+        sheet.set_cell(1, cell(CellFormula::Literal, "4", &[]));
+        sheet.set_cell(2, cell(CellFormula::CellsSum, "0, 1", &[]));
+        sheet.set_cell(3, cell(CellFormula::CellsRatio, "2;1", &[]));
+        assert_eq!(value(&sheet, 2), "10");
+        assert_eq!(value(&sheet, 3), "2.5");
+
+        let changed = sheet.set_cell(1, cell(CellFormula::Literal, "0", &[]));
+        assert_eq!(changed, [2, 3]);
+        assert_eq!(value(&sheet, 2), "6");
+        assert_eq!(value(&sheet, 3), ERR_DIV_ZERO);
+    }
+
+    #[test]
+    fn tag_formulas() {
+        let mut sheet = Sheet::default();
+        sheet.set_cell(0, cell(CellFormula::TagSum, "a", &[]));
+        sheet.set_cell(1, cell(CellFormula::TagCount, "a", &[]));
+        sheet.set_cell(2, cell(CellFormula::Literal, "3", &["a"]));
+        sheet.set_cell(3, cell(CellFormula::Literal, "text", &["a"]));
+        sheet.set_cell(4, cell(CellFormula::Literal, "5", &["a"]));
+        assert_eq!(value(&sheet, 0), "8");
+        assert_eq!(value(&sheet, 1), "3");
+
+        // untagging must update the aggregates too
+        let changed = sheet.set_cell(4, cell(CellFormula::Literal, "5", &[]));
+        assert_eq!(changed, [0, 1]);
+        assert_eq!(value(&sheet, 0), "3");
+    }
+
+    #[test]
+    fn edits_replace_dependencies() {
+        let mut sheet = Sheet::default();
+        sheet.set_cell(0, cell(CellFormula::Literal, "1", &["a"]));
+        sheet.set_cell(1, cell(CellFormula::Literal, "2", &["b"]));
+        sheet.set_cell(2, cell(CellFormula::CellsSum, "0", &[]));
+        sheet.set_cell(3, cell(CellFormula::TagSum, "a", &[]));
+
+        sheet.set_cell(2, cell(CellFormula::CellsSum, "1", &[]));
+        sheet.set_cell(3, cell(CellFormula::TagSum, "b", &[]));
+        assert_eq!((value(&sheet, 2), value(&sheet, 3)), ("2", "2"));
+
+        // the former inputs aren't read anymore
+        assert!(sheet.set_cell(0, cell(CellFormula::Literal, "5", &["a"])).is_empty());
+        assert_eq!(sheet.set_cell(1, cell(CellFormula::Literal, "3", &["b"])), [2, 3]);
+
+        // cells replayed with their value
+        let mut replayed = cell(CellFormula::Literal, "4", &["b"]);
+        replayed.value = "4".into();
+        sheet.restore_cell(4, replayed);
+        assert_eq!(sheet.set_cell(1, cell(CellFormula::Literal, "6", &[])), [2, 3]);
+        assert_eq!(value(&sheet, 3), "4");
+    }
+
+    #[test]
+    fn errors() {
+        let mut sheet = Sheet::default();
+        sheet.set_cell(0, cell(CellFormula::CellsSum, "7", &[]));
+        sheet.set_cell(1, cell(CellFormula::CellsSum, "x", &[]));
+        sheet.set_cell(2, cell(CellFormula::Literal, "text", &[]));
+        sheet.set_cell(3, cell(CellFormula::CellsSum, "2", &[]));
+        sheet.set_cell(4, cell(CellFormula::TagMean, "none", &[]));
+        sheet.set_cell(5, cell(CellFormula::Literal, "4", &[]));
+        sheet.set_cell(6, cell(CellFormula::CellSqrt, "5 5", &[]));
+        assert_eq!(value(&sheet, 0), ERR_REF);
+        assert_eq!(value(&sheet, 1), ERR_REF);
+        assert_eq!(value(&sheet, 3), ERR_VALUE);
+        assert_eq!(value(&sheet, 4), ERR_EMPTY);
+        assert_eq!(value(&sheet, 6), ERR_VALUE);
+
+        // referenced cells appearing later resolve the error
+        sheet.set_cell(7, cell(CellFormula::Literal, "1", &[]));
+        assert_eq!(value(&sheet, 0), "1");
+
+        sheet.set_cell(6, cell(CellFormula::CellSqrt, "5", &[]));
+        assert_eq!(value(&sheet, 6), "2");
+    }
+
+    #[test]
+    fn cycles() {
+        let mut sheet = Sheet::default();
+        sheet.set_cell(0, cell(CellFormula::CellsSum, "1", &[]));
+        sheet.set_cell(2, cell(CellFormula::CellsSum, "0", &[]));
+        sheet.set_cell(1, cell(CellFormula::CellsSum, "0", &[]));
+        sheet.set_cell(3, cell(CellFormula::CellsSum, "3", &[]));
+        assert_eq!(value(&sheet, 0), ERR_CYCLE);
+        assert_eq!(value(&sheet, 1), ERR_CYCLE);
+        // depends on the cycle
+        assert_eq!(value(&sheet, 2), ERR_CYCLE);
+        assert_eq!(value(&sheet, 3), ERR_CYCLE);
+
+        // breaking the cycle
+        sheet.set_cell(1, cell(CellFormula::Literal, "2", &[]));
+        assert_eq!(value(&sheet, 0), "2");
+        assert_eq!(value(&sheet, 2), "2");
+    }
+
+    #[test]
+    fn long_chains() {
+        const LEN: IndexInEntity = 100_000;
+
+        let mut sheet = Sheet::default();
+        for i in 1..LEN {
+            sheet.cells.insert(i, cell(CellFormula::CellsSum, &(i - 1).to_string(), &[]));
+        }
+
+        let changed = sheet.set_cell(0, cell(CellFormula::Literal, "1", &[]));
+        assert_eq!(changed.len(), LEN as usize - 1);
+        assert_eq!(value(&sheet, LEN - 1), "1");
+    }
+}
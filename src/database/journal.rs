@@ -0,0 +1,606 @@
+use serde::{Serialize, Deserialize};
+use futures_lite::AsyncWriteExt;
+use async_channel::{Sender, Receiver, unbounded};
+use async_fs::{File, OpenOptions, read_to_string, rename, remove_file};
+
+use crate::config::{config, JOURNAL, JOURNAL_OLD};
+
+use super::{Database, EntityId};
+use super::objects::{
+    Conversation, Bucket, Sheet, Document, User, UserId, TokenRecord,
+    Username, Email, Hash, Message, Cell, Element, File as BucketFile,
+};
+use super::entities::{Entity, EntityData, EntityAccess, Change};
+use super::update::{Update, UpdateType};
+
+use std::sync::OnceLock;
+use std::fmt::Debug;
+
+static TX_JOURNAL: OnceLock<Sender<JournalMessage>> = OnceLock::new();
+
+/// A mutation of the database, as written in the journal
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "entry", content = "data")]
+#[serde(rename_all = "kebab-case")]
+pub enum JournalEntry {
+    /// Content update, with its history record if it has an author
+    Commit(Option<Change>, Update),
+    NewEntity(EntityId, EntityData),
+    /// Everything but the revision; an author of `UserId::MAX`
+    /// means that the entity was abandoned and reset.
+    Metadata(EntityId, EntityData),
+    /// Whole `Entity<User>`, sessions excluded
+    User(UserId, serde_json::Value),
+    /// Access of a user to an entity; `None` once dropped
+    Access(UserId, EntityId, Option<EntityAccess>),
+    Tokens(UserId, Vec<TokenRecord>),
+    /// Copies of a file charged to a user, then their storage use
+    Storage(UserId, Hash, usize, usize),
+    Username(Username, Option<UserId>),
+    Email(Email, Option<UserId>),
+    FileRc(Hash, usize),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    seq: u64,
+    #[serde(flatten)]
+    entry: JournalEntry,
+}
+
+pub enum JournalMessage {
+    Entry(JournalEntry),
+    /// Start a new journal file; the sender is notified when done
+    Rotate(Sender<()>),
+}
+
+/// Kind of object stored in an `Entities` collection
+pub trait EntityKind: Debug + Default + Serialize {
+    fn entity_id(raw_id: u32) -> EntityId;
+
+    /// Replays a content update
+    fn apply(&mut self, _update: &Update) -> Result<(), &'static str> {
+        Err("unexpected update for this entity type")
+    }
+}
+
+/// Queues an entry; call this while holding the lock
+/// which protects the mutated data, to keep entries ordered.
+/// Entries are numbered by the journal task, in queue order.
+///
+/// The journal task writes and fsyncs queued entries in batches,
+/// shortly after they're appended but without holding the caller
+/// back: a crash can lose the latest changes, even though clients
+/// were told that they succeeded. The journal then ends with the
+/// last batch which made it to the disk.
+pub fn append(entry: JournalEntry) {
+    if let Some(tx_journal) = TX_JOURNAL.get() {
+        let _ = tx_journal.try_send(JournalMessage::Entry(entry));
+    }
+}
+
+/// Journals a whole user; prefer the smaller entries below
+/// for frequent changes.
+pub fn save_user(user_id: UserId, user: &Entity<User>) {
+    match serde_json::to_value(user) {
+        Ok(value) => append(JournalEntry::User(user_id, value)),
+        Err(e) => println!("journal: failed to serialize user {}: {:?}", user_id, e),
+    }
+}
+
+pub fn save_access(user_id: UserId, user: &Entity<User>, entity_id: EntityId) {
+    let access = user.secret.entities.get(&entity_id).cloned();
+    append(JournalEntry::Access(user_id, entity_id, access));
+}
+
+pub fn save_tokens(user_id: UserId, user: &Entity<User>) {
+    append(JournalEntry::Tokens(user_id, user.tokens.clone()));
+}
+
+pub fn save_storage(user_id: UserId, user: &Entity<User>, hash: &Hash) {
+    let storage = &user.secret.storage;
+    let copies = storage.copies.get(hash).copied().unwrap_or(0);
+    append(JournalEntry::Storage(user_id, hash.clone(), copies, storage.used));
+}
+
+/// Closes the current journal file so that it can be compacted into
+/// the next snapshot; entries queued afterwards go to a new file.
+pub async fn rotate() {
+    let Some(tx_journal) = TX_JOURNAL.get() else {
+        return;
+    };
+
+    let (tx_done, rx_done) = unbounded();
+    let _ = tx_journal.send(JournalMessage::Rotate(tx_done)).await;
+    let _ = rx_done.recv().await;
+}
+
+/// Called after a snapshot covering the rotated journal was written
+pub async fn discard_rotated() {
+    let _ = remove_file(config().database_path(JOURNAL_OLD)).await;
+}
+
+/// Replays the entries of a journal file which follow `after_seq`,
+/// and returns the sequence number of the last one. A torn last
+/// line is cut off, so that new entries don't get appended to it.
+pub async fn replay_file(db: &Database, path: &str, after_seq: u64) -> u64 {
+    let mut last_seq = after_seq;
+
+    let Ok(text) = read_to_string(path).await else {
+        return last_seq;
+    };
+
+    let mut valid_len = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            valid_len += line.len();
+            continue;
+        }
+
+        let record: Record = match serde_json::from_str(line) {
+            Ok(record) => record,
+            // most likely torn by a crash while writing
+            Err(e) => {
+                println!("journal: ignoring bad record in {}: {:?}", path, e);
+                break;
+            },
+        };
+
+        valid_len += line.len();
+        if record.seq > last_seq {
+            last_seq = record.seq;
+            db.replay(record.entry).await;
+        }
+    }
+
+    if valid_len < text.len() {
+        let _ = async_fs::write(path, &text[..valid_len]).await;
+    }
+
+    last_seq
+}
+
+/// Replays entries which aren't part of the snapshot, then
+/// returns the journal task. Must run before any new entry.
+pub async fn init(snapshot_seq: u64) -> impl std::future::Future<Output = ()> {
+    let mut last_seq = snapshot_seq;
+
+    for name in [JOURNAL_OLD, JOURNAL] {
+        let path = config().database_path(name);
+        last_seq = replay_file(&crate::DATABASE, &path, last_seq).await;
+    }
+
+    println!("journal: replayed up to entry {}", last_seq);
+
+    let (tx_journal, rx_journal) = unbounded();
+    let _ = TX_JOURNAL.set(tx_journal);
+    journal_task(rx_journal, last_seq + 1)
+}
+
+async fn open_journal() -> File {
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    options.open(config().database_path(JOURNAL)).await.unwrap()
+}
+
+async fn journal_task(rx_journal: Receiver<JournalMessage>, mut next_seq: u64) {
+    let mut file = open_journal().await;
+    let mut batch = Vec::new();
+
+    while let Ok(message) = rx_journal.recv().await {
+        let mut next = Some(message);
+
+        // group every queued entry in a single write + fsync
+        while let Some(message) = next.take() {
+            match message {
+                JournalMessage::Entry(entry) => push_record(&mut batch, &mut next_seq, entry),
+                JournalMessage::Rotate(tx_done) => {
+                    flush(&mut file, &mut batch).await;
+                    file = rotate_file(file).await;
+                    let _ = tx_done.send(()).await;
+                },
+            }
+
+            next = rx_journal.try_recv().ok();
+        }
+
+        flush(&mut file, &mut batch).await;
+    }
+}
+
+/// Numbers an entry and serializes it at the end of `batch`
+fn push_record(batch: &mut Vec<u8>, next_seq: &mut u64, entry: JournalEntry) {
+    let record = Record { seq: *next_seq, entry };
+
+    match serde_json::to_writer(&mut *batch, &record) {
+        Ok(()) => {
+            batch.push(b'\n');
+            *next_seq += 1;
+        },
+        Err(e) => println!("journal: failed to serialize entry {}: {:?}", record.seq, e),
+    }
+}
+
+async fn flush(file: &mut File, batch: &mut Vec<u8>) {
+    if batch.is_empty() {
+        return;
+    }
+
+    if let Err(e) = file.write_all(batch).await {
+        println!("journal: write failed: {:?}", e);
+    }
+
+    if let Err(e) = file.sync_data().await {
+        println!("journal: fsync failed: {:?}", e);
+    }
+
+    batch.clear();
+}
+
+async fn rotate_file(file: File) -> File {
+    drop(file);
+
+    let path = config().database_path(JOURNAL);
+    let old_path = config().database_path(JOURNAL_OLD);
+
+    // a previous rotation wasn't followed by a snapshot: keep both
+    if let Ok(text) = read_to_string(&old_path).await {
+        let current = read_to_string(&path).await.unwrap_or_default();
+        let merged = text + &current;
+        let _ = async_fs::write(&old_path, merged).await;
+        let _ = remove_file(&path).await;
+    } else if let Err(e) = rename(&path, &old_path).await {
+        println!("journal: rotation failed: {:?}", e);
+    }
+
+    open_journal().await
+}
+
+impl Database {
+    async fn replay(&self, entry: JournalEntry) {
+        let result = match entry {
+            JournalEntry::Commit(change, update) => match update.id {
+                EntityId::Conversation(id) => self.conversations.replay(id, change, &update).await,
+                EntityId::Bucket(id) => self.buckets.replay(id, change, &update).await,
+                EntityId::Spreadsheet(id) => self.sheets.replay(id, change, &update).await,
+                EntityId::Document(id) => self.documents.replay(id, change, &update).await,
+                EntityId::User(id) => self.users.replay(id, change, &update).await,
+            },
+            JournalEntry::NewEntity(entity_id, metadata) => match entity_id {
+                EntityId::Conversation(id) => self.conversations.replay_new(id, metadata).await,
+                EntityId::Bucket(id) => self.buckets.replay_new(id, metadata).await,
+                EntityId::Spreadsheet(id) => self.sheets.replay_new(id, metadata).await,
+                EntityId::Document(id) => self.documents.replay_new(id, metadata).await,
+                EntityId::User(id) => self.users.replay_new(id, metadata).await,
+            },
+            JournalEntry::Metadata(entity_id, metadata) => match entity_id {
+                EntityId::Conversation(id) => self.conversations.replay_metadata(id, metadata).await,
+                EntityId::Bucket(id) => self.buckets.replay_metadata(id, metadata).await,
+                EntityId::Spreadsheet(id) => self.sheets.replay_metadata(id, metadata).await,
+                EntityId::Document(id) => self.documents.replay_metadata(id, metadata).await,
+                EntityId::User(id) => self.users.replay_metadata(id, metadata).await,
+            },
+            JournalEntry::User(user_id, value) => match serde_json::from_value(value) {
+                Ok(user) => self.users.replay_entity(user_id, user).await,
+                Err(_) => Err("invalid user record"),
+            },
+            JournalEntry::Access(user_id, entity_id, access) => {
+                self.users.replay_inner(user_id, |user| match access {
+                    Some(access) => _ = user.secret.entities.insert(entity_id, access),
+                    None => _ = user.secret.entities.remove(&entity_id),
+                }).await
+            },
+            JournalEntry::Tokens(user_id, tokens) => {
+                self.users.replay_inner(user_id, |user| user.tokens = tokens).await
+            },
+            JournalEntry::Storage(user_id, hash, copies, used) => {
+                self.users.replay_inner(user_id, |user| {
+                    let storage = &mut user.secret.storage;
+                    match copies {
+                        0 => _ = storage.copies.remove(&hash),
+                        _ => _ = storage.copies.insert(hash, copies),
+                    }
+                    storage.used = used;
+                }).await
+            },
+            JournalEntry::Username(name, Some(user_id)) => {
+                self.usernames.write().await.insert(name, user_id);
+                Ok(())
+            },
+            JournalEntry::Username(name, None) => {
+                self.usernames.write().await.remove(&name);
+                Ok(())
+            },
+            JournalEntry::Email(email, Some(user_id)) => {
+                self.emails.write().await.insert(email, user_id);
+                Ok(())
+            },
+            JournalEntry::Email(email, None) => {
+                self.emails.write().await.remove(&email);
+                Ok(())
+            },
+            JournalEntry::FileRc(hash, counter) => {
+                self.file_rc.write().await.insert(hash, counter);
+                Ok(())
+            },
+        };
+
+        if let Err(msg) = result {
+            println!("journal: failed to replay entry: {}", msg);
+        }
+    }
+}
+
+fn data<T: for<'de> Deserialize<'de>>(update: &Update) -> Result<T, &'static str> {
+    serde_json::from_value(update.data.clone()).map_err(|_| "invalid update data")
+}
+
+fn set<T>(vec: &mut [T], index: u64, item: T) -> Result<(), &'static str> {
+    let slot = vec.get_mut(index as usize).ok_or("bad index")?;
+    *slot = item;
+    Ok(())
+}
+
+impl EntityKind for Conversation {
+    fn entity_id(raw_id: u32) -> EntityId {
+        EntityId::Conversation(raw_id)
+    }
+
+    fn apply(&mut self, update: &Update) -> Result<(), &'static str> {
+        let message: Message = data(update)?;
+        match update.update_type {
+            UpdateType::NewMessage => self.messages.push(message),
+            UpdateType::SetMessage => set(&mut self.messages, update.index, message)?,
+            _ => return Err("unexpected update for a conversation"),
+        }
+        Ok(())
+    }
+}
+
+impl EntityKind for Sheet {
+    fn entity_id(raw_id: u32) -> EntityId {
+        EntityId::Spreadsheet(raw_id)
+    }
+
+    fn apply(&mut self, update: &Update) -> Result<(), &'static str> {
+        let cell: Cell = data(update)?;
+        match update.update_type {
+            UpdateType::SetCell => self.restore_cell(update.index, cell),
+            _ => return Err("unexpected update for a spreadsheet"),
+        }
+        Ok(())
+    }
+}
+
+impl EntityKind for Document {
+    fn entity_id(raw_id: u32) -> EntityId {
+        EntityId::Document(raw_id)
+    }
+
+    fn apply(&mut self, update: &Update) -> Result<(), &'static str> {
+        let index = update.index as usize;
+        let bad_index = index > self.elements.len();
+        match update.update_type {
+            UpdateType::NewElement if !bad_index => {
+                self.elements.insert(index, data::<Element>(update)?);
+            },
+            UpdateType::SetElement => set(&mut self.elements, update.index, data(update)?)?,
+            UpdateType::ByeElement if index < self.elements.len() => {
+                self.elements.remove(index);
+            },
+            _ => return Err("unexpected update for a document"),
+        }
+        Ok(())
+    }
+}
+
+impl EntityKind for Bucket {
+    fn entity_id(raw_id: u32) -> EntityId {
+        EntityId::Bucket(raw_id)
+    }
+
+    fn apply(&mut self, update: &Update) -> Result<(), &'static str> {
+        match update.update_type {
+            UpdateType::NewFile => self.files.push(data::<BucketFile>(update)?),
+            UpdateType::SetFile => set(&mut self.files, update.index, data(update)?)?,
+            UpdateType::ByeFile if (update.index as usize) < self.files.len() => {
+                self.files.remove(update.index as usize);
+            },
+            UpdateType::SetBucketQuota => self.quota = data(update)?,
+            _ => return Err("unexpected update for a bucket"),
+        }
+        Ok(())
+    }
+}
+
+impl EntityKind for User {
+    fn entity_id(raw_id: u32) -> EntityId {
+        EntityId::User(raw_id)
+    }
+
+    fn apply(&mut self, update: &Update) -> Result<(), &'static str> {
+        match update.update_type {
+            UpdateType::SetUser => self.public = data(update)?,
+            // the whole user is journaled along with these
+            UpdateType::ByeInvite | UpdateType::NewFriend => (),
+            _ => return Err("unexpected update for a user"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::objects::AssociatedImage;
+    use futures_lite::future::block_on;
+
+    use std::path::{Path, PathBuf};
+
+    /// Writes records to a journal file of its own
+    fn journal(name: &str, records: Vec<(u64, JournalEntry)>) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("kolab-{}-{}.jsonl", name, std::process::id()));
+        let mut text = String::new();
+
+        for (seq, entry) in records {
+            text += &serde_json::to_string(&Record { seq, entry }).unwrap();
+            text.push('\n');
+        }
+
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn replay(db: &Database, path: &Path, after_seq: u64) -> u64 {
+        block_on(replay_file(db, path.to_str().unwrap(), after_seq))
+    }
+
+    fn username(name: &str, user_id: Option<UserId>) -> JournalEntry {
+        JournalEntry::Username(name.into(), user_id)
+    }
+
+    fn user_id(db: &Database, name: &str) -> Option<UserId> {
+        block_on(db.user_id(&name.into()))
+    }
+
+    #[test]
+    fn entries_are_replayed_in_order() {
+        let db = Database::init();
+        let path = journal("order", vec![
+            (1, username("alice", Some(1))),
+            (2, username("alice", None)),
+            (3, username("alice", Some(3))),
+            (4, username("bob", Some(4))),
+            (5, username("bob", None)),
+        ]);
+
+        assert_eq!(replay(&db, &path, 0), 5);
+        assert_eq!(user_id(&db, "alice"), Some(3));
+        assert_eq!(user_id(&db, "bob"), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn entries_are_numbered_in_queue_order() {
+        let (tx_journal, rx_journal) = unbounded();
+
+        // appends racing each other
+        let threads: Vec<_> = (0..2).map(|thread| {
+            let tx_journal = tx_journal.clone();
+            std::thread::spawn(move || for i in 0..100 {
+                let entry = JournalEntry::FileRc(format!("{}-{}", thread, i), 1);
+                tx_journal.try_send(JournalMessage::Entry(entry)).unwrap();
+            })
+        }).collect();
+
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+
+        let mut batch = Vec::new();
+        let mut next_seq = 1;
+        while let Ok(JournalMessage::Entry(entry)) = rx_journal.try_recv() {
+            push_record(&mut batch, &mut next_seq, entry);
+        }
+
+        let path = journal("queue", Vec::new());
+        std::fs::write(&path, batch).unwrap();
+
+        // none of them is mistaken for an older entry
+        let db = Database::init();
+        assert_eq!(replay(&db, &path, 0), 200);
+        assert_eq!(block_on(db.file_rc.read()).len(), 200);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn snapshot_entries_are_skipped() {
+        let db = Database::init();
+        let path = journal("skip", vec![
+            (1, username("alice", Some(1))),
+            (2, username("bob", Some(2))),
+            (3, username("carol", Some(3))),
+        ]);
+
+        assert_eq!(replay(&db, &path, 2), 3);
+        assert_eq!(user_id(&db, "alice"), None);
+        assert_eq!(user_id(&db, "bob"), None);
+        assert_eq!(user_id(&db, "carol"), Some(3));
+
+        // nothing new
+        assert_eq!(replay(&db, &path, 3), 3);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rotated_journal_comes_first() {
+        let db = Database::init();
+        let old = journal("rotated-old", vec![
+            (1, username("alice", Some(1))),
+            (2, username("alice", None)),
+        ]);
+
+        // an entry of the old journal can't be replayed twice
+        let current = journal("rotated-current", vec![
+            (2, username("alice", None)),
+            (3, username("alice", Some(7))),
+        ]);
+
+        let last_seq = replay(&db, &old, 0);
+        assert_eq!(replay(&db, &current, last_seq), 3);
+        assert_eq!(user_id(&db, "alice"), Some(7));
+
+        std::fs::remove_file(old).unwrap();
+        std::fs::remove_file(current).unwrap();
+    }
+
+    #[test]
+    fn torn_entries_are_cut_off() {
+        let db = Database::init();
+        let path = journal("torn", vec![(1, username("alice", Some(1)))]);
+        let valid = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, valid.clone() + r#"{"seq":2,"entry":"username","da"#).unwrap();
+
+        assert_eq!(replay(&db, &path, 0), 1);
+        assert_eq!(user_id(&db, "alice"), Some(1));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), valid);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn commits_follow_their_entity() {
+        let metadata = EntityData {
+            image: AssociatedImage::Picture(String::new()),
+            author: 0,
+            guests: Vec::new(),
+            revision: 0,
+            banned: Vec::new(),
+        };
+
+        let id = EntityId::Conversation(0);
+        let message = |content: &str| Message {
+            author: 0,
+            content: content.into(),
+            created: 0,
+            extended: false,
+        };
+
+        let first = Update::new(UpdateType::NewMessage, id, 1, 0, &message("first"));
+        let second = Update::new(UpdateType::NewMessage, id, 2, 1, &message("second"));
+        let edit = Update::new(UpdateType::SetMessage, id, 3, 0, &message("edited"));
+
+        let db = Database::init();
+        let path = journal("commits", vec![
+            (1, JournalEntry::NewEntity(id, metadata)),
+            (2, JournalEntry::Commit(None, first)),
+            (3, JournalEntry::Commit(None, second)),
+            (4, JournalEntry::Commit(None, edit)),
+        ]);
+
+        assert_eq!(replay(&db, &path, 0), 4);
+        let arc_conv = block_on(db.conversations.find(0)).unwrap();
+        let conv = block_on(arc_conv.read());
+        let contents: Vec<_> = conv.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["edited", "second"]);
+        assert_eq!(conv.metadata.revision, 3);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -3,17 +3,21 @@ use litemap::LiteMap;
 
 use crate::serde_utils::SerdeRwLock as RwLock;
 
-use objects::{Conversation, Bucket, Sheet, Document, User, Username, Hash};
+use objects::{Conversation, Bucket, Sheet, Document, User, Username, Email, Hash};
 use objects::{UserId, ConvId, DocumentId, BucketId, SheetId};
-use entities::{Entities, EntityData};
+use entities::{Entities, EntityData, Revision, Change, MetadataChange};
+use journal::JournalEntry;
 use update::Update;
 
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::fmt::Debug;
 use std::iter::once;
 
 pub mod update;
 pub mod objects;
+pub mod formula;
+pub mod journal;
 pub mod entities;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -41,7 +45,13 @@ pub struct Database {
     pub documents: Entities<Document>,
     pub users: Entities<User>,
     pub usernames: RwLock<LiteMap<Username, UserId>>,
+    /// verified email addresses only
+    #[serde(default)]
+    pub emails: RwLock<LiteMap<Email, UserId>>,
     pub file_rc: RwLock<LiteMap<Hash, usize>>,
+    /// last journal entry included in this snapshot
+    #[serde(default)]
+    pub journal_seq: AtomicU64,
 }
 
 impl Database {
@@ -53,7 +63,9 @@ impl Database {
             documents: Entities::init(),
             users: Entities::init(),
             usernames: RwLock::new(LiteMap::new()),
+            emails: RwLock::new(LiteMap::new()),
             file_rc: RwLock::new(LiteMap::new()),
+            journal_seq: AtomicU64::new(0),
         }
     }
 
@@ -91,7 +103,8 @@ impl Database {
         }
     }
 
-    pub async fn push_guest(&self, entity_id: EntityId, guest: UserId) {
+    /// Returns the updates to notify
+    pub async fn push_guest(&self, entity_id: EntityId, guest: UserId) -> Vec<Update> {
         match entity_id {
             EntityId::Conversation(id) => self.conversations.try_push_guest(id, guest).await,
             EntityId::Bucket(id) => self.buckets.try_push_guest(id, guest).await,
@@ -101,7 +114,8 @@ impl Database {
         }
     }
 
-    pub async fn drop_access(&self, entity_id: EntityId, guest: UserId) {
+    /// Returns the updates to notify
+    pub async fn drop_access(&self, entity_id: EntityId, guest: UserId) -> Vec<Update> {
         match entity_id {
             EntityId::Conversation(id) => self.conversations.try_drop_access(id, guest).await,
             EntityId::Bucket(id) => self.buckets.try_drop_access(id, guest).await,
@@ -121,8 +135,79 @@ impl Database {
         }
     }
 
-    pub async fn load_from_json(&self, saved_db: &str) {
-        let reference: Self = serde_json::from_str(&saved_db).unwrap();
+    pub async fn update_metadata<F>(&self, entity_id: EntityId, f: F) -> Result<(), &'static str>
+    where
+        F: FnOnce(&mut EntityData) -> Result<(), &'static str>,
+    {
+        match entity_id {
+            EntityId::Conversation(id) => self.conversations.update_metadata(id, f).await,
+            EntityId::Bucket(id) => self.buckets.update_metadata(id, f).await,
+            EntityId::Spreadsheet(id) => self.sheets.update_metadata(id, f).await,
+            EntityId::Document(id) => self.documents.update_metadata(id, f).await,
+            EntityId::User(id) => self.users.update_metadata(id, f).await,
+        }
+    }
+
+    pub async fn commit_metadata<F>(
+        &self,
+        entity_id: EntityId,
+        author: UserId,
+        f: F,
+    ) -> Result<Vec<Update>, &'static str>
+    where
+        F: FnOnce(&mut EntityData) -> Result<Vec<MetadataChange>, &'static str>,
+    {
+        match entity_id {
+            EntityId::Conversation(id) => self.conversations.commit_metadata(id, author, f).await,
+            EntityId::Bucket(id) => self.buckets.commit_metadata(id, author, f).await,
+            EntityId::Spreadsheet(id) => self.sheets.commit_metadata(id, author, f).await,
+            EntityId::Document(id) => self.documents.commit_metadata(id, author, f).await,
+            EntityId::User(id) => self.users.commit_metadata(id, author, f).await,
+        }
+    }
+
+    pub async fn user_id(&self, username: &Username) -> Option<UserId> {
+        let reader = self.usernames.read().await;
+        reader.get(username).copied()
+    }
+
+    pub async fn user_id_by_email(&self, email: &Email) -> Option<UserId> {
+        let reader = self.emails.read().await;
+        reader.get(email).copied()
+    }
+
+    pub async fn history(
+        &self,
+        entity_id: EntityId,
+        since: Revision,
+        limit: usize,
+    ) -> Option<Vec<(Revision, Change)>> {
+        match entity_id {
+            EntityId::Conversation(id) => self.conversations.history(id, since, limit).await,
+            EntityId::Bucket(id) => self.buckets.history(id, since, limit).await,
+            EntityId::Spreadsheet(id) => self.sheets.history(id, since, limit).await,
+            EntityId::Document(id) => self.documents.history(id, since, limit).await,
+            EntityId::User(id) => self.users.history(id, since, limit).await,
+        }
+    }
+
+    pub async fn updates_since(
+        &self,
+        entity_id: EntityId,
+        rev: Revision,
+    ) -> Option<(Revision, Vec<Update>)> {
+        match entity_id {
+            EntityId::Conversation(id) => self.conversations.updates_since(id, rev).await,
+            EntityId::Bucket(id) => self.buckets.updates_since(id, rev).await,
+            EntityId::Spreadsheet(id) => self.sheets.updates_since(id, rev).await,
+            EntityId::Document(id) => self.documents.updates_since(id, rev).await,
+            EntityId::User(id) => self.users.updates_since(id, rev).await,
+        }
+    }
+
+    /// Returns the sequence number of the last journal entry in this snapshot
+    pub async fn load_from_json(&self, saved_db: &str) -> u64 {
+        let reference: Self = serde_json::from_str(saved_db).unwrap();
         self.conversations.restore(reference.conversations).await;
         self.buckets.restore(reference.buckets).await;
         self.sheets.restore(reference.sheets).await;
@@ -132,6 +217,18 @@ impl Database {
         let mut src = reference.usernames.write().await;
         let mut dst = self.usernames.write().await;
         *dst = std::mem::take(&mut src);
+
+        let mut src = reference.emails.write().await;
+        let mut dst = self.emails.write().await;
+        *dst = std::mem::take(&mut src);
+
+        let mut src = reference.file_rc.write().await;
+        let mut dst = self.file_rc.write().await;
+        *dst = std::mem::take(&mut src);
+
+        let journal_seq = reference.journal_seq.load(Ordering::SeqCst);
+        self.journal_seq.store(journal_seq, Ordering::SeqCst);
+        journal_seq
     }
 
     pub async fn inc_file_rc(&self, hash: &Hash) {
@@ -141,12 +238,15 @@ impl Database {
         } else {
             file_rc.insert(hash.clone(), 1);
         }
+
+        journal::append(JournalEntry::FileRc(hash.clone(), file_rc[hash]));
     }
 
     pub async fn dec_file_rc(&self, hash: &Hash) {
         let mut file_rc = self.file_rc.write().await;
         if let Some(counter) = file_rc.get_mut(hash) {
             *counter -= 1;
+            journal::append(JournalEntry::FileRc(hash.clone(), *counter));
         }
     }
 }
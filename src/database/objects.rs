@@ -1,12 +1,17 @@
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
 use async_channel::Sender;
+use sha2::{Digest, Sha256};
 use litemap::LiteMap;
 
 use crate::session::SessionId;
+use crate::config::config;
+use crate::{to_hex, totp};
 use super::{EntityId, InviteData};
 use super::entities::{EntityAccess, IndexInEntity};
 use super::update::Update;
+use super::formula::Graph;
 
+use std::time::{SystemTime, Duration};
 use std::sync::Arc;
 
 pub type Username = String;
@@ -16,6 +21,7 @@ pub type Email = String;
 pub type Hash = String;
 
 pub type UserId = u32;
+pub type TokenId = u32;
 pub type ConvId = u32;
 pub type BucketId = u32;
 pub type DocumentId = u32;
@@ -49,6 +55,41 @@ pub struct MessageExtension {
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Bucket {
     pub files: Vec<File>,
+    /// Set by the author, in bytes
+    #[serde(default)]
+    pub quota: Option<usize>,
+}
+
+impl Bucket {
+    /// Whether `file` fits in the quota, replacing the one at `replaced`
+    pub fn fits(&self, file: &File, replaced: Option<usize>) -> bool {
+        let Some(quota) = self.quota else {
+            return true;
+        };
+
+        let mut others = self.files.iter().enumerate().filter(|(i, _)| Some(*i) != replaced);
+        let added = match others.any(|(_, other)| other.sha256 == file.sha256) {
+            true => 0,
+            false => file.size,
+        };
+
+        self.usage(replaced) + added <= quota
+    }
+
+    /// Size of the distinct files, leaving out the one at `skip`
+    pub fn usage(&self, skip: Option<usize>) -> usize {
+        let mut seen = Vec::new();
+        let mut usage = 0;
+
+        for (i, file) in self.files.iter().enumerate() {
+            if Some(i) != skip && !seen.contains(&&file.sha256) {
+                seen.push(&file.sha256);
+                usage += file.size;
+            }
+        }
+
+        usage
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,18 +98,27 @@ pub struct File {
     pub sha256: Hash,
     pub size: usize,
     pub uploaded: Stamp,
+    /// Charged for this file; None if uploaded before quotas existed
+    #[serde(default)]
+    pub uploader: Option<UserId>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Sheet {
     pub cells: LiteMap<IndexInEntity, Cell>,
+    /// Built on the first edit, then kept up to date
+    #[serde(skip)]
+    pub(super) graph: Option<Graph>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cell {
-    text: String,
-    formula: CellFormula,
-    tags: Vec<CellTag>,
+    pub text: String,
+    pub formula: CellFormula,
+    pub tags: Vec<CellTag>,
+    /// computed by the server
+    #[serde(default)]
+    pub value: String,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -99,9 +149,69 @@ pub struct User {
     pub public: UserData,
     pub secret: SecretUserData,
     // internal user data:
-    pub tokens: Vec<Token>,
+    #[serde(deserialize_with = "deserialize_tokens")]
+    pub tokens: Vec<TokenRecord>,
     #[serde(skip)]
     pub sessions: LiteMap<SessionId, Sender<Arc<Update>>>,
+    /// Token which opened each session
+    #[serde(skip)]
+    pub session_tokens: LiteMap<SessionId, TokenId>,
+}
+
+/// An authentication token; the token itself isn't stored, only its hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenRecord {
+    pub id: TokenId,
+    pub hash: Hash,
+    /// Describes the device which requested the token
+    pub label: String,
+    pub created: Stamp,
+    pub last_used: Stamp,
+    /// Pushed back every time the token is used
+    pub expires: Stamp,
+}
+
+/// What the owner of a token gets to see
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenInfo {
+    pub id: TokenId,
+    pub label: String,
+    pub created: Stamp,
+    pub last_used: Stamp,
+    pub expires: Stamp,
+    /// True for the token of the requesting session
+    pub current: bool,
+}
+
+/// Tokens used to be stored in plain form
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredToken {
+    Record(TokenRecord),
+    Legacy(Token),
+}
+
+fn deserialize_tokens<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<TokenRecord>, D::Error> {
+    let stored = Vec::<StoredToken>::deserialize(deserializer)?;
+    let now = now_stamp();
+
+    let records = stored.into_iter().zip(1..).map(|(token, id)| match token {
+        StoredToken::Record(record) => record,
+        StoredToken::Legacy(token) => TokenRecord {
+            id,
+            hash: hash_token(&token),
+            label: "Unknown device".into(),
+            created: now,
+            last_used: now,
+            expires: now + config().token_lifetime(),
+        },
+    });
+
+    Ok(records.collect())
+}
+
+fn hash_token(token: &str) -> Hash {
+    to_hex(Sha256::digest(token.as_bytes()).into())
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -128,9 +238,132 @@ pub struct SecretUserData {
     pub password_salt: String,
     pub server_admin: bool,
     pub max_file_size: usize,
+    #[serde(default)]
+    pub reset_code: Option<ResetCode>,
+    #[serde(default)]
+    pub two_factor: Option<TwoFactor>,
+    /// Set by admins; disabled users can't log in
+    #[serde(default)]
+    pub disabled: bool,
+    #[serde(default = "default_storage_quota")]
+    pub storage_quota: usize,
+    #[serde(default)]
+    pub storage: StorageUsage,
+}
+
+fn default_storage_quota() -> usize {
+    config().storage_quota
+}
+
+/// Files uploaded by a user; a file stored several times,
+/// in one bucket or more, is charged once.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StorageUsage {
+    pub used: usize,
+    /// Number of stored copies of each charged file
+    pub copies: LiteMap<Hash, usize>,
+}
+
+impl StorageUsage {
+    pub fn charge(&mut self, file: &File, quota: usize) -> Result<(), &'static str> {
+        let copies = self.copies.get(&file.sha256).copied().unwrap_or(0);
+
+        if copies == 0 {
+            if self.used + file.size > quota {
+                return Err("Storage quota exceeded");
+            }
+
+            self.used += file.size;
+        }
+
+        self.copies.insert(file.sha256.clone(), copies + 1);
+        Ok(())
+    }
+
+    pub fn refund(&mut self, file: &File) {
+        let Some(copies) = self.copies.get_mut(&file.sha256) else {
+            return;
+        };
+
+        *copies -= 1;
+        if *copies == 0 {
+            self.copies.remove(&file.sha256);
+            self.used = self.used.saturating_sub(file.size);
+        }
+    }
+}
+
+/// One-time code issued by an admin, to reset a forgotten password
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResetCode {
+    pub hash: Hash,
+    pub expires: Stamp,
+}
+
+impl ResetCode {
+    /// Returns the code in plain form, along with its record
+    pub fn new(lifetime: Stamp) -> (String, Self) {
+        let code = to_hex(rand::random())[..16].to_string();
+        let record = Self {
+            hash: hash_token(&code),
+            expires: now_stamp() + lifetime,
+        };
+
+        (code, record)
+    }
+
+    pub fn matches(&self, code: &str) -> bool {
+        self.expires > now_stamp() && self.hash == hash_token(code.trim())
+    }
+}
+
+const RECOVERY_CODES: usize = 10;
+
+/// TOTP second factor, required by `GetToken` once enabled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwoFactor {
+    /// base32, as shown to authenticator apps
+    pub secret: String,
+    /// Hashes of the unused recovery codes
+    pub recovery_codes: Vec<Hash>,
+    /// Last accepted time step
+    pub last_step: u64,
+}
+
+impl TwoFactor {
+    pub fn new(secret: String, step: u64) -> Self {
+        Self {
+            secret,
+            recovery_codes: Vec::new(),
+            last_step: step,
+        }
+    }
+
+    /// Replaces the recovery codes, returning them in plain form
+    pub fn new_recovery_codes(&mut self) -> Vec<String> {
+        let codes: Vec<_> = (0..RECOVERY_CODES)
+            .map(|_| to_hex(rand::random())[..10].to_string())
+            .collect();
+
+        self.recovery_codes = codes.iter().map(|code| hash_token(code)).collect();
+        codes
+    }
+
+    /// Accepts a TOTP code or consumes a recovery code
+    pub fn check(&mut self, code: &str) -> bool {
+        if let Some(step) = totp::verify(&self.secret, code, self.last_step) {
+            self.last_step = step;
+            return true;
+        }
+
+        let hash = hash_token(&code.trim().to_ascii_lowercase());
+        let len = self.recovery_codes.len();
+        self.recovery_codes.retain(|recovery| *recovery != hash);
+        self.recovery_codes.len() < len
+    }
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CellFormula {
     Literal,
     TagSum,
@@ -150,13 +383,87 @@ pub enum CellFormula {
     CellSqrt,
 }
 
+pub fn now_stamp() -> Stamp {
+    SystemTime::UNIX_EPOCH.elapsed().unwrap_or(Duration::ZERO).as_secs()
+}
+
 impl User {
-    pub fn set_tx_update(&mut self, session_id: usize, tx_update: Sender<Arc<Update>>) {
+    pub fn set_tx_update(&mut self, session_id: usize, token_id: TokenId, tx_update: Sender<Arc<Update>>) {
         self.sessions.insert(session_id, tx_update);
+        self.session_tokens.insert(session_id, token_id);
     }
 
     pub fn end_of_session(&mut self, session_id: usize) {
         self.sessions.remove(&session_id);
+        self.session_tokens.remove(&session_id);
+    }
+
+    /// Returns a new token; expired ones are dropped meanwhile
+    pub fn new_token(&mut self, label: String) -> Token {
+        let now = now_stamp();
+        self.tokens.retain(|record| record.expires > now);
+
+        let token = to_hex(rand::random());
+        let id = self.tokens.iter().map(|record| record.id).max().unwrap_or(0) + 1;
+
+        self.tokens.push(TokenRecord {
+            id,
+            hash: hash_token(&token),
+            label,
+            created: now,
+            last_used: now,
+            expires: now + config().token_lifetime(),
+        });
+
+        token
+    }
+
+    /// Checks a token and extends its lifetime
+    pub fn use_token(&mut self, token: &Token) -> Option<TokenId> {
+        let now = now_stamp();
+        let hash = hash_token(token);
+        let record = self.tokens.iter_mut().find(|r| r.hash == hash && r.expires > now)?;
+
+        record.last_used = now;
+        record.expires = now + config().token_lifetime();
+        Some(record.id)
+    }
+
+    /// Removes tokens, and returns the sessions they had opened
+    pub fn revoke_tokens<F: Fn(&TokenRecord) -> bool>(&mut self, revoke: F) -> Vec<Sender<Arc<Update>>> {
+        let mut revoked = Vec::new();
+        self.tokens.retain(|record| match revoke(record) {
+            true => {
+                revoked.push(record.id);
+                false
+            },
+            false => true,
+        });
+
+        let sessions = self.session_tokens.iter().filter(|(_, id)| revoked.contains(id));
+        let session_ids: Vec<SessionId> = sessions.map(|(session_id, _)| *session_id).collect();
+
+        let mut senders = Vec::new();
+        for session_id in session_ids {
+            senders.extend(self.sessions.get(&session_id).cloned());
+            self.end_of_session(session_id);
+        }
+
+        senders
+    }
+
+    pub fn token_infos(&self, current: Option<TokenId>) -> Vec<TokenInfo> {
+        let now = now_stamp();
+        let valid = self.tokens.iter().filter(|record| record.expires > now);
+
+        valid.map(|record| TokenInfo {
+            id: record.id,
+            label: record.label.clone(),
+            created: record.created,
+            last_used: record.last_used,
+            expires: record.expires,
+            current: Some(record.id) == current,
+        }).collect()
     }
 }
 
@@ -171,3 +478,71 @@ impl AssociatedImage {
         Self::Gradient([c1, c2])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(hash: &str, size: usize) -> File {
+        File {
+            name: format!("{hash}.bin"),
+            sha256: hash.into(),
+            size,
+            uploaded: 0,
+            uploader: Some(0),
+        }
+    }
+
+    #[test]
+    fn storage_is_charged_once_per_file() {
+        let mut storage = StorageUsage::default();
+        let (a, b) = (file("a", 600), file("b", 500));
+
+        storage.charge(&a, 1000).unwrap();
+        storage.charge(&a, 1000).unwrap();
+        assert_eq!(storage.used, 600);
+        assert_eq!(storage.copies[&a.sha256], 2);
+
+        assert_eq!(storage.charge(&b, 1000), Err("Storage quota exceeded"));
+        assert!(!storage.copies.contains_key(&b.sha256));
+
+        storage.refund(&a);
+        assert_eq!(storage.used, 600);
+        storage.refund(&a);
+        assert_eq!(storage.used, 0);
+        assert!(storage.copies.is_empty());
+
+        // never charged: nothing to refund
+        storage.refund(&b);
+        assert_eq!(storage.used, 0);
+
+        storage.charge(&b, 1000).unwrap();
+        assert_eq!(storage.used, 500);
+    }
+
+    #[test]
+    fn buckets_count_distinct_files() {
+        let mut bucket = Bucket {
+            files: vec![file("a", 600), file("a", 600), file("b", 300)],
+            quota: Some(1000),
+        };
+
+        assert_eq!(bucket.usage(None), 900);
+        assert_eq!(bucket.usage(Some(0)), 900);
+        assert_eq!(bucket.usage(Some(2)), 600);
+
+        // another copy is free, a new file isn't
+        assert!(bucket.fits(&file("a", 600), None));
+        assert!(bucket.fits(&file("c", 100), None));
+        assert!(!bucket.fits(&file("c", 101), None));
+
+        // replacing the only copy of b frees its size
+        assert!(bucket.fits(&file("c", 400), Some(2)));
+        assert!(!bucket.fits(&file("c", 401), Some(2)));
+        // replacing one copy of a doesn't
+        assert!(!bucket.fits(&file("c", 101), Some(0)));
+
+        bucket.quota = None;
+        assert!(bucket.fits(&file("c", usize::MAX), None));
+    }
+}
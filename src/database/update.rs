@@ -1,16 +1,19 @@
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 
 use super::{EntityId, UserId};
 use super::entities::{Revision, IndexInEntity};
 use super::objects::{UserData, Cell, SheetId};
 
-#[derive(Debug, Serialize)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum UpdateType {
     SetUser,
     NewInvite,
+    /// An invite was accepted or discarded
+    ByeInvite,
     NewGuest,
     ByeGuest,
+    NewOwner,
     NewFriend,
     NewMessage,
     SetMessage,
@@ -21,9 +24,20 @@ pub enum UpdateType {
     NewFile,
     SetFile,
     ByeFile,
+    SetBucketQuota,
+    ServerShutdown,
+    FrontReload,
+    TokenRevoked,
 }
 
-#[derive(Debug, Serialize)]
+impl UpdateType {
+    /// Changes of the guest list or author, which don't touch the content
+    pub fn is_metadata(self) -> bool {
+        matches!(self, UpdateType::NewGuest | UpdateType::ByeGuest | UpdateType::NewOwner)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Update {
     #[serde(rename = "type")]
     pub update_type: UpdateType,
@@ -54,9 +68,9 @@ impl Update {
         Self::new(UpdateType::SetUser, EntityId::User(user_id), rev, 0, data)
     }
 
-    pub fn friend(sender_id: UserId, receiver_id: UserId) -> Self {
+    pub fn friend(sender_id: UserId, receiver_id: UserId, rev: Revision) -> Self {
         let rcv = receiver_id as u64;
-        Self::new(UpdateType::NewFriend, EntityId::User(sender_id), 0, rcv, &())
+        Self::new(UpdateType::NewFriend, EntityId::User(sender_id), rev, rcv, &())
     }
 
     pub fn cell(sheet_id: SheetId, rev: Revision, index: IndexInEntity, data: &Cell) -> Self {
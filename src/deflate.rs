@@ -0,0 +1,300 @@
+use async_tungstenite::tungstenite::protocol::frame::{Frame, FrameHeader};
+use async_tungstenite::tungstenite::protocol::frame::coding::{OpCode, Data};
+use futures_lite::{AsyncRead, AsyncWrite};
+use flate2::{write::DeflateEncoder, Compression, Decompress, FlushDecompress, Status};
+
+use std::io::{self, Cursor, Write, ErrorKind};
+use std::task::{Context, Poll};
+use std::pin::Pin;
+
+/// Accepted offer: both sides compress each message on its own,
+/// so that no state is kept between messages (RFC 7692, 7.1.1)
+pub const RESPONSE: &str = "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+/// Tail of a sync flush, left out of compressed messages (RFC 7692, 7.2.1)
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Same as tungstenite's largest frame, also the
+/// limit of an inflated message
+const MAX_FRAME: usize = 16 << 20;
+
+const READ_CHUNK: usize = 16 << 10;
+
+/// Checks the values of `Sec-WebSocket-Extensions` for a
+/// permessage-deflate offer which `RESPONSE` can accept
+pub fn offered<'a>(mut values: impl Iterator<Item = &'a str>) -> bool {
+    values.any(|value| value.split(',').any(|offer| {
+        let mut params = offer.split(';').map(str::trim);
+        let name = params.next().unwrap_or("");
+
+        name.eq_ignore_ascii_case("permessage-deflate") && params.all(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            match key.trim().to_ascii_lowercase().as_str() {
+                "server_no_context_takeover" | "client_no_context_takeover" => true,
+                // the client's window isn't restricted
+                "client_max_window_bits" => true,
+                // flate2 always compresses with a 32K window
+                "server_max_window_bits" => value.trim().trim_matches('"') == "15",
+                _ => false,
+            }
+        })
+    }))
+}
+
+/// Compresses a message payload; the frame carrying it must set RSV1
+pub fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.flush()?;
+
+    // taken before the encoder finishes the stream
+    let mut compressed = std::mem::take(encoder.get_mut());
+    if compressed.ends_with(&TAIL) {
+        compressed.truncate(compressed.len() - TAIL.len());
+    }
+
+    Ok(compressed)
+}
+
+/// Inflates the compressed messages sent by a client before
+/// tungstenite reads them, as it rejects frames with RSV1.
+/// Writes are passed through.
+pub struct Stream<S> {
+    inner: S,
+    enabled: bool,
+    /// Bytes read from `inner`, up to a complete frame
+    input: Vec<u8>,
+    /// Frames ready to be read, and how much was read already
+    output: Vec<u8>,
+    output_pos: usize,
+    /// Some while a compressed message is fragmented
+    message: Option<Message>,
+}
+
+struct Message {
+    decompress: Decompress,
+    inflated: usize,
+}
+
+impl<S> Stream<S> {
+    pub fn new(inner: S, enabled: bool) -> Self {
+        Self {
+            inner,
+            enabled,
+            input: Vec::new(),
+            output: Vec::new(),
+            output_pos: 0,
+            message: None,
+        }
+    }
+
+    /// Moves the first frame of `input`, if it's complete, to `output`.
+    /// Returns false if more bytes are needed.
+    fn next_frame(&mut self) -> io::Result<bool> {
+        let mut cursor = Cursor::new(&self.input);
+        let Some((header, length)) = FrameHeader::parse(&mut cursor).map_err(invalid)? else {
+            return Ok(false);
+        };
+
+        if length > MAX_FRAME as u64 {
+            return Err(invalid("frame too long"));
+        }
+
+        let start = cursor.position() as usize;
+        let end = start + length as usize;
+
+        if self.input.len() < end {
+            return Ok(false);
+        }
+
+        let compressed = match header.opcode {
+            OpCode::Data(Data::Continue) => self.message.is_some(),
+            OpCode::Data(_) if header.rsv1 => {
+                self.message = Some(Message {
+                    decompress: Decompress::new(false),
+                    inflated: 0,
+                });
+
+                true
+            },
+            _ => false,
+        };
+
+        if !compressed {
+            self.output.extend(self.input.drain(..end));
+            return Ok(true);
+        }
+
+        let mut payload = self.input[start..end].to_vec();
+        self.input.drain(..end);
+
+        if let Some(mask) = header.mask {
+            payload.iter_mut().zip(mask.iter().cycle()).for_each(|(byte, key)| *byte ^= key);
+        }
+
+        if header.is_final {
+            payload.extend(TAIL);
+        }
+
+        let message = self.message.as_mut().unwrap();
+        let inflated = message.inflate(&payload)?;
+        if header.is_final {
+            self.message = None;
+        }
+
+        // masked again, as tungstenite expects from clients
+        let header = FrameHeader { rsv1: false, ..header };
+        Frame::from_payload(header, inflated).format(&mut self.output).map_err(invalid)?;
+        Ok(true)
+    }
+}
+
+impl Message {
+    fn inflate(&mut self, mut input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(input.len() * 4);
+
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+
+            let total_in = self.decompress.total_in();
+            let status = self.decompress.decompress_vec(input, &mut output, FlushDecompress::Sync)
+                .map_err(invalid)?;
+            input = &input[(self.decompress.total_in() - total_in) as usize..];
+
+            if self.inflated + output.len() > MAX_FRAME {
+                return Err(invalid("inflated message too long"));
+            }
+
+            let done = input.is_empty() && output.len() < output.capacity();
+            if done || status == Status::StreamEnd {
+                break;
+            }
+        }
+
+        self.inflated += output.len();
+        Ok(output)
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, error)
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Stream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.enabled {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        while this.output_pos == this.output.len() {
+            this.output.clear();
+            this.output_pos = 0;
+
+            if this.next_frame()? {
+                continue;
+            }
+
+            let len = this.input.len();
+            this.input.resize(len + READ_CHUNK, 0);
+            let read = Pin::new(&mut this.inner).poll_read(cx, &mut this.input[len..]);
+            let read = match read {
+                Poll::Ready(Ok(read)) => read,
+                other => {
+                    this.input.truncate(len);
+                    return other;
+                },
+            };
+
+            this.input.truncate(len + read);
+
+            // a partial frame is left for tungstenite to report
+            if read == 0 {
+                this.output = std::mem::take(&mut this.input);
+                if this.output.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+            }
+        }
+
+        let available = &this.output[this.output_pos..];
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        this.output_pos += read;
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Stream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::AsyncReadExt;
+    use async_tungstenite::tungstenite::protocol::frame::coding::Control;
+
+    #[test]
+    fn offers() {
+        assert!(offered(["permessage-deflate; client_max_window_bits"].into_iter()));
+        assert!(offered(["x-webkit-deflate-frame", "permessage-deflate"].into_iter()));
+        assert!(offered(["permessage-deflate; server_max_window_bits=10, permessage-deflate"].into_iter()));
+        assert!(!offered(["permessage-deflate; server_max_window_bits=10"].into_iter()));
+        assert!(!offered(["permessage-deflate; unknown"].into_iter()));
+        assert!(!offered([].into_iter()));
+    }
+
+    fn frame(opcode: OpCode, rsv1: bool, is_final: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = Some([1, 2, 3, 4]);
+        let header = FrameHeader { is_final, rsv1, opcode, mask, ..FrameHeader::default() };
+        let mut bytes = Vec::new();
+        Frame::from_payload(header, payload.to_vec()).format(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn compressed_frames_are_inflated() {
+        let text = "hello ".repeat(1000);
+        let compressed = compress(text.as_bytes()).unwrap();
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+
+        // a fragmented compressed message, a ping in between, then a plain message
+        let mut input = frame(OpCode::Data(Data::Text), true, false, first);
+        input.extend(frame(OpCode::Control(Control::Ping), false, true, b"ping"));
+        input.extend(frame(OpCode::Data(Data::Continue), false, true, second));
+        input.extend(frame(OpCode::Data(Data::Text), false, true, b"plain"));
+
+        let mut output = Vec::new();
+        futures_lite::future::block_on(Stream::new(input.as_slice(), true).read_to_end(&mut output)).unwrap();
+
+        let mut cursor = Cursor::new(&output);
+        let mut payloads = Vec::new();
+        while let Some((header, length)) = FrameHeader::parse(&mut cursor).unwrap() {
+            assert!(!header.rsv1);
+            let start = cursor.position() as usize;
+            let mut payload = output[start..start + length as usize].to_vec();
+            let mask = header.mask.unwrap();
+            payload.iter_mut().zip(mask.iter().cycle()).for_each(|(byte, key)| *byte ^= key);
+            payloads.push(payload);
+            cursor.set_position((start + length as usize) as u64);
+        }
+
+        let inflated = [payloads[0].as_slice(), &payloads[2]].concat();
+        assert_eq!(inflated, text.as_bytes());
+        assert_eq!(payloads[1], b"ping");
+        assert_eq!(payloads[3], b"plain");
+    }
+}
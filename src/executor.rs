@@ -1,12 +1,29 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicU8, AtomicUsize, AtomicU64, Ordering, fence};
+use std::sync::{Arc, Mutex, RwLock, Condvar, LazyLock, OnceLock};
 use std::task::{Poll, Wake, Waker, Context};
-use std::thread::{spawn, sleep};
-use std::pin::{pin, Pin};
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::cell::RefCell;
 use std::future::Future;
-use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::thread::spawn;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
 
 use async_channel::Receiver;
+use serde::Serialize;
+
+/// Polls longer than this are reported, as they stall their runner
+const SLOW_POLL: Duration = Duration::from_millis(50);
+
+// states of a task
+/// Waiting for a wake
+const IDLE: u8 = 0;
+/// In a queue
+const SCHEDULED: u8 = 1;
+/// Being polled by a runner
+const RUNNING: u8 = 2;
+/// Woken while being polled: queued again once the poll returns
+const NOTIFIED: u8 = 3;
 
 pub struct Task {
     inner: Pin<Box<dyn Future<Output = ()> + Send>>
@@ -18,64 +35,278 @@ impl<F: Future<Output = ()> + Send + 'static> From<F> for Task {
     }
 }
 
-#[derive(Debug, Default)]
-struct Running {
-    inner: AtomicBool,
+/// A task along with its own waker state
+struct TaskCell {
+    /// None once the task has completed
+    task: Mutex<Option<Task>>,
+    /// A task is queued once at most, and never while it's
+    /// polled, so that runners don't contend for it
+    state: AtomicU8,
 }
 
-impl Running {
-    fn reset(&self) {
-        self.inner.store(false, Ordering::SeqCst);
-    }
+impl Wake for TaskCell {
+    fn wake(self: Arc<Self>) {
+        let mut state = self.state.load(Ordering::SeqCst);
+
+        loop {
+            let next = match state {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                _ => return,
+            };
 
-    fn read(&self) -> bool {
-        self.inner.load(Ordering::SeqCst)
+            match self.state.compare_exchange(state, next, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(IDLE) => return EXECUTOR.push(self),
+                Ok(_) => return,
+                Err(actual) => state = actual,
+            }
+        }
     }
 }
 
-impl Wake for Running {
-    fn wake(self: Arc<Self>) {
-        self.inner.store(true, Ordering::SeqCst);
-    }
+type Queue = Mutex<VecDeque<Arc<TaskCell>>>;
+
+/// Ready queue of a runner thread
+#[derive(Default)]
+struct Worker {
+    index: usize,
+    queue: Queue,
+    polls: AtomicU64,
+    completed: AtomicU64,
+    poll_time_us: AtomicU64,
+    slowest_poll_us: AtomicU64,
 }
 
-pub fn runner(rx_tasks: Receiver<Task>) {
-    let running = Arc::new(Running::default());
-    let waker = Waker::from(running.clone());
-
-    let mut receiver = pin!(rx_tasks.recv());
-    let mut can_receive_tasks = true;
-    let mut has_tasks = false;
-    let mut tasks = Vec::new();
-    running.wake_by_ref();
-
-    while can_receive_tasks || has_tasks {
-        if !running.read() {
-            sleep(Duration::from_millis(5));
-            continue;
+#[derive(Debug, Clone, Serialize)]
+pub struct RunnerMetrics {
+    pub queued: usize,
+    pub polls: u64,
+    /// Tasks which completed on this runner
+    pub completed: u64,
+    pub poll_time_us: u64,
+    pub slowest_poll_us: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Metrics {
+    pub live_tasks: usize,
+    /// Tasks sent to `rx_tasks` but not picked up yet
+    pub pending_tasks: usize,
+    pub runners: Vec<RunnerMetrics>,
+}
+
+#[derive(Default)]
+struct Executor {
+    /// Tasks woken from outside the runner threads
+    injector: Queue,
+    workers: RwLock<Vec<Arc<Worker>>>,
+    rx_tasks: OnceLock<Receiver<Task>>,
+    /// Tasks which haven't completed yet
+    live_tasks: AtomicUsize,
+    /// Runners waiting for a task
+    parked: AtomicUsize,
+    park_lock: Mutex<()>,
+    park_cvar: Condvar,
+}
+
+static EXECUTOR: LazyLock<Executor> = LazyLock::new(Executor::default);
+
+thread_local! {
+    static CURRENT_WORKER: RefCell<Option<Arc<Worker>>> = const { RefCell::new(None) };
+}
+
+impl Executor {
+    fn spawn(&self, task: Task) {
+        self.live_tasks.fetch_add(1, Ordering::SeqCst);
+        let cell = TaskCell {
+            task: Mutex::new(Some(task)),
+            state: AtomicU8::new(SCHEDULED),
+        };
+
+        self.push(Arc::new(cell));
+    }
+
+    /// Queues a woken task on the current runner if there is one,
+    /// and wakes up a parked runner which may steal it.
+    fn push(&self, cell: Arc<TaskCell>) {
+        CURRENT_WORKER.with_borrow(|worker| match worker {
+            Some(worker) => worker.queue.lock().unwrap().push_back(cell),
+            None => self.injector.lock().unwrap().push_back(cell),
+        });
+
+        // pairs with the one in `park`
+        fence(Ordering::SeqCst);
+
+        if self.parked.load(Ordering::SeqCst) > 0 {
+            let _guard = self.park_lock.lock().unwrap();
+            self.park_cvar.notify_one();
+        }
+    }
+
+    fn register(&self) -> Arc<Worker> {
+        let mut workers = self.workers.write().unwrap();
+        let worker = Arc::new(Worker {
+            index: workers.len(),
+            ..Default::default()
+        });
+
+        workers.push(worker.clone());
+        CURRENT_WORKER.set(Some(worker.clone()));
+        worker
+    }
+
+    fn next_task(&self, worker: &Worker) -> Option<Arc<TaskCell>> {
+        if let Some(cell) = worker.queue.lock().unwrap().pop_front() {
+            return Some(cell);
         }
 
-        running.reset();
+        if let Some(cell) = self.injector.lock().unwrap().pop_front() {
+            return Some(cell);
+        }
+
+        self.steal(worker)
+    }
+
+    /// Takes half of the tasks queued on the busiest other runner
+    fn steal(&self, thief: &Worker) -> Option<Arc<TaskCell>> {
+        let workers = self.workers.read().unwrap();
+        let others = workers.iter().filter(|w| !std::ptr::eq(&***w, thief));
+        let victim = others.max_by_key(|w| w.queue.lock().unwrap().len())?;
+
+        let mut stolen = {
+            let mut queue = victim.queue.lock().unwrap();
+            let half = queue.len() / 2;
+            queue.split_off(half)
+        };
+
+        let first = stolen.pop_front();
+        thief.queue.lock().unwrap().append(&mut stolen);
+        first
+    }
+
+    fn has_queued_tasks(&self) -> bool {
+        let workers = self.workers.read().unwrap();
+        let mut queues = workers.iter().map(|w| &w.queue).chain([&self.injector]);
+        queues.any(|queue| !queue.lock().unwrap().is_empty())
+    }
+
+    /// Blocks until a task is pushed. Returns false when
+    /// every task has completed.
+    fn park(&self) -> bool {
+        let guard = self.park_lock.lock().unwrap();
+
+        // a push either sees this runner parked, and notifies it
+        // under the lock, or is seen by the check below
+        self.parked.fetch_add(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+
+        let running = match self.has_queued_tasks() {
+            true => true,
+            false if self.live_tasks.load(Ordering::SeqCst) == 0 => false,
+            false => {
+                let _guard = self.park_cvar.wait(guard).unwrap();
+                true
+            },
+        };
+
+        self.parked.fetch_sub(1, Ordering::SeqCst);
+        running
+    }
+
+    fn run(&self, worker: &Worker, cell: Arc<TaskCell>) {
+        // only this runner holds the task until the poll returns
+        let Ok(mut slot) = cell.task.lock() else {
+            return;
+        };
+
+        let Some(task) = slot.as_mut() else {
+            return;
+        };
+
+        cell.state.store(RUNNING, Ordering::SeqCst);
+
+        let waker = Waker::from(cell.clone());
         let mut context = Context::from_waker(&waker);
 
-        can_receive_tasks = loop {
-            match Future::poll(receiver.as_mut(), &mut context) {
-                Poll::Ready(Ok(new_task)) => tasks.push(new_task),
-                Poll::Ready(Err(_)) => break false,
-                Poll::Pending => break true,
-            }
+        let start = Instant::now();
+        let poll = catch_unwind(AssertUnwindSafe(|| task.inner.as_mut().poll(&mut context)));
+        let elapsed = start.elapsed();
+
+        let elapsed_us = elapsed.as_micros() as u64;
+        worker.polls.fetch_add(1, Ordering::Relaxed);
+        worker.poll_time_us.fetch_add(elapsed_us, Ordering::Relaxed);
+        worker.slowest_poll_us.fetch_max(elapsed_us, Ordering::Relaxed);
+
+        if elapsed > SLOW_POLL {
+            println!("executor: slow poll on runner {} ({:?})", worker.index, elapsed);
+        }
+
+        let done = match poll {
+            Ok(Poll::Ready(())) => true,
+            Ok(Poll::Pending) => false,
+            // the task is dropped, the runner carries on
+            Err(_) => {
+                println!("executor: a task panicked on runner {}", worker.index);
+                true
+            },
         };
 
-        let mut i = 0;
-        while i < tasks.len() {
-            let fut = tasks[i].inner.as_mut();
-            match Future::poll(fut, &mut context) {
-                Poll::Ready(()) => _ = tasks.swap_remove(i),
-                Poll::Pending => i += 1,
+        if done {
+            *slot = None;
+            worker.completed.fetch_add(1, Ordering::Relaxed);
+
+            if self.live_tasks.fetch_sub(1, Ordering::SeqCst) == 1 {
+                let _guard = self.park_lock.lock().unwrap();
+                self.park_cvar.notify_all();
             }
+
+            return;
         }
 
-        has_tasks = !tasks.is_empty();
+        drop(slot);
+
+        // woken during the poll
+        if cell.state.compare_exchange(RUNNING, IDLE, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            cell.state.store(SCHEDULED, Ordering::SeqCst);
+            self.push(cell);
+        }
+    }
+}
+
+/// Runs tasks until the channel is closed and every task has completed
+pub fn runner(rx_tasks: Receiver<Task>) {
+    let worker = EXECUTOR.register();
+    let _ = EXECUTOR.rx_tasks.set(rx_tasks.clone());
+
+    EXECUTOR.spawn(Task::from(async move {
+        while let Ok(task) = rx_tasks.recv().await {
+            EXECUTOR.spawn(task);
+        }
+    }));
+
+    loop {
+        match EXECUTOR.next_task(&worker) {
+            Some(cell) => EXECUTOR.run(&worker, cell),
+            None if EXECUTOR.park() => continue,
+            None => break,
+        }
+    }
+}
+
+pub fn metrics() -> Metrics {
+    let workers = EXECUTOR.workers.read().unwrap();
+    let runners = workers.iter().map(|worker| RunnerMetrics {
+        queued: worker.queue.lock().unwrap().len(),
+        polls: worker.polls.load(Ordering::Relaxed),
+        completed: worker.completed.load(Ordering::Relaxed),
+        poll_time_us: worker.poll_time_us.load(Ordering::Relaxed),
+        slowest_poll_us: worker.slowest_poll_us.load(Ordering::Relaxed),
+    });
+
+    Metrics {
+        live_tasks: EXECUTOR.live_tasks.load(Ordering::SeqCst),
+        pending_tasks: EXECUTOR.rx_tasks.get().map_or(0, |rx| rx.len()),
+        runners: runners.collect(),
     }
 }
 
@@ -83,3 +314,31 @@ pub fn spawn_runner(rx_tasks: &Receiver<Task>) {
     let rx_tasks = rx_tasks.clone();
     spawn(|| runner(rx_tasks));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_channel::unbounded;
+
+    #[test]
+    fn panicking_tasks_are_dropped() {
+        let (tx_tasks, rx_tasks) = unbounded();
+        let (tx_done, rx_done) = unbounded();
+
+        tx_tasks.try_send(Task::from(async { panic!("expected") })).unwrap();
+        for i in 0..4 {
+            let tx_done = tx_done.clone();
+            tx_tasks.try_send(Task::from(async move {
+                futures_lite::future::yield_now().await;
+                tx_done.send(i).await.unwrap();
+            })).unwrap();
+        }
+
+        // the runner returns once every task has completed
+        drop(tx_tasks);
+        runner(rx_tasks);
+
+        assert_eq!(rx_done.len(), 4);
+        assert_eq!(metrics().live_tasks, 0);
+    }
+}
@@ -0,0 +1,163 @@
+use async_channel::{Sender, Receiver, unbounded};
+use async_fs::{read, read_to_string, metadata};
+use async_lock::RwLock;
+use async_io::Timer;
+use serde::Deserialize;
+use blocking::unblock;
+
+use crate::{config::config, http::Asset, StringifyError};
+
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime};
+
+/// Lists the sources of each asset, relative to the front directory
+const MANIFEST: &str = "manifest.json";
+
+/// How often the watcher looks for modified sources
+const WATCH_PERIOD_MS: u64 = 500;
+
+pub static INDEX_HTML: RwLock<Asset> = RwLock::new(Asset::new());
+pub static STYLE_CSS: RwLock<Asset> = RwLock::new(Asset::new());
+pub static MAIN_JS: RwLock<Asset> = RwLock::new(Asset::new());
+
+/// Closed, then replaced, when clients should reload the page
+static RELOAD: LazyLock<RwLock<(Sender<()>, Receiver<()>)>> = LazyLock::new(|| RwLock::new(unbounded()));
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(rename = "index.html")]
+    index_html: Vec<String>,
+    #[serde(rename = "style.css")]
+    style_css: Vec<String>,
+    #[serde(rename = "main.js")]
+    main_js: Vec<String>,
+}
+
+impl Manifest {
+    async fn read() -> Result<Self, String> {
+        let json = read_to_string(config().front_path(MANIFEST)).await.fmt_err("manifest")?;
+        serde_json::from_str(&json).fmt_err("manifest")
+    }
+
+    /// Each asset, along with its sources
+    fn assets(&self) -> [(&'static RwLock<Asset>, &[String]); 3] {
+        [
+            (&INDEX_HTML, &self.index_html),
+            (&STYLE_CSS, &self.style_css),
+            (&MAIN_JS, &self.main_js),
+        ]
+    }
+}
+
+async fn build(sources: &[String]) -> Result<Asset, String> {
+    let mut asset = Asset::new();
+
+    for source in sources {
+        let path = config().front_path(source);
+        let src = read(&path).await.fmt_err(source)?;
+        let modified = metadata(&path).await.and_then(|m| m.modified());
+        asset.append(&src, modified.unwrap_or(SystemTime::now()));
+    }
+
+    // the best brotli quality takes a while
+    let asset = unblock(move || {
+        asset.compress();
+        asset
+    });
+
+    Ok(asset.await)
+}
+
+/// (Re)builds every asset from the manifest. On error, the
+/// previous assets are kept.
+pub async fn load_assets() -> Result<(), String> {
+    let manifest = Manifest::read().await?;
+
+    let mut built = Vec::new();
+    for (_, sources) in manifest.assets() {
+        built.push(build(sources).await?);
+    }
+
+    for ((asset, _), new_asset) in manifest.assets().into_iter().zip(built) {
+        *asset.write().await = new_asset;
+    }
+
+    Ok(())
+}
+
+async fn modified(path: &str) -> Option<SystemTime> {
+    metadata(config().front_path(path)).await.and_then(|m| m.modified()).ok()
+}
+
+/// Modification times of the sources of an asset
+async fn fingerprint(sources: &[String]) -> Vec<Option<SystemTime>> {
+    let mut times = Vec::with_capacity(sources.len());
+    for source in sources {
+        times.push(modified(source).await);
+    }
+
+    times
+}
+
+/// Dev mode: rebuilds the assets whose sources change
+pub async fn watch_task() {
+    let mut manifest_time = None;
+    let mut manifest = None;
+    // sources and their modification times, for each asset
+    let mut last: Vec<(Vec<String>, Vec<Option<SystemTime>>)> = Vec::new();
+
+    loop {
+        let current_time = modified(MANIFEST).await;
+        if current_time != manifest_time {
+            match Manifest::read().await {
+                Ok(new_manifest) => {
+                    manifest = Some(new_manifest);
+                    manifest_time = current_time;
+                },
+                Err(msg) => println!("front-end reload failed: {msg}"),
+            }
+        }
+
+        let mut reloaded = false;
+        for (i, (asset, sources)) in manifest.iter().flat_map(Manifest::assets).enumerate() {
+            let current = (sources.to_vec(), fingerprint(sources).await);
+
+            // assets were built at startup
+            let Some(previous) = last.get_mut(i) else {
+                last.push(current);
+                continue;
+            };
+
+            if *previous == current {
+                continue;
+            }
+
+            // retried on the next pass until it builds
+            match build(sources).await {
+                Ok(new_asset) => {
+                    *asset.write().await = new_asset;
+                    *previous = current;
+                    reloaded = true;
+                },
+                Err(msg) => println!("front-end reload failed: {msg}"),
+            }
+        }
+
+        if reloaded {
+            println!("front-end reloaded");
+        }
+
+        if reloaded && config().reload_clients {
+            let mut reload = RELOAD.write().await;
+            let (tx, _) = std::mem::replace(&mut *reload, unbounded());
+            tx.close();
+        }
+
+        Timer::after(Duration::from_millis(WATCH_PERIOD_MS)).await;
+    }
+}
+
+/// Channel which gets closed when clients should reload the page
+pub async fn reload_receiver() -> Receiver<()> {
+    RELOAD.read().await.1.clone()
+}
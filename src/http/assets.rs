@@ -0,0 +1,174 @@
+use async_lock::RwLock;
+use sha2::{Digest, Sha256};
+use flate2::{write::GzEncoder, Compression};
+use brotli::enc::BrotliEncoderParams;
+
+use crate::to_hex;
+
+use super::cache::Validators;
+use super::parser::Request;
+use super::response::Response;
+
+use std::time::SystemTime;
+use std::io::Write;
+
+/// File of the front-end, served from memory
+pub struct Asset {
+    bytes: Vec<u8>,
+    gzip: Vec<u8>,
+    brotli: Vec<u8>,
+    /// Hash of the bytes appended so far
+    hasher: Option<Sha256>,
+    modified: SystemTime,
+}
+
+impl Asset {
+    pub const fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            gzip: Vec::new(),
+            brotli: Vec::new(),
+            hasher: None,
+            modified: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    /// Appends a source file which was last modified at `modified`
+    pub fn append(&mut self, src: &[u8], modified: SystemTime) {
+        self.bytes.extend_from_slice(src);
+        self.bytes.extend_from_slice(b"\r\n");
+
+        let hasher = self.hasher.get_or_insert_with(Sha256::new);
+        hasher.update(src);
+        hasher.update(b"\r\n");
+        self.modified = self.modified.max(modified);
+    }
+
+    /// Each encoding is a distinct representation, with its own tag
+    fn etag(&self, coding: Option<&str>) -> String {
+        let hash = to_hex(self.hasher.clone().unwrap_or_default().finalize().into());
+        match coding {
+            Some(coding) => format!("\"{hash}-{coding}\""),
+            None => format!("\"{hash}\""),
+        }
+    }
+
+    /// Builds the compressed copies, once all sources were appended
+    pub fn compress(&mut self) {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+        gzip.write_all(&self.bytes).unwrap();
+        self.gzip = gzip.finish().unwrap();
+
+        let params = BrotliEncoderParams::default();
+        self.brotli.clear();
+        brotli::BrotliCompress(&mut self.bytes.as_slice(), &mut self.brotli, &params).unwrap();
+    }
+
+    /// Picks the smallest copy that the client accepts
+    fn negotiate(&self, request: &Request) -> (&[u8], Option<&'static str>) {
+        let header = request.header("Accept-Encoding").unwrap_or("");
+
+        if !self.brotli.is_empty() && quality(header, "br") > 0.0 {
+            (&self.brotli, Some("br"))
+        } else if !self.gzip.is_empty() && quality(header, "gzip") > 0.0 {
+            (&self.gzip, Some("gzip"))
+        } else {
+            (&self.bytes, None)
+        }
+    }
+}
+
+/// Weight given to a content-coding by an Accept-Encoding header
+fn quality(header: &str, coding: &str) -> f32 {
+    let mut wildcard = 0.0;
+
+    for item in header.split(',') {
+        let mut params = item.split(';').map(str::trim);
+        let name = params.next().unwrap_or("");
+        let q = params.find_map(|p| p.strip_prefix("q="));
+        let q = q.map_or(Some(1.0), |q| q.parse().ok()).unwrap_or(0.0);
+
+        if name.eq_ignore_ascii_case(coding) {
+            return q;
+        } else if name == "*" {
+            wildcard = q;
+        }
+    }
+
+    wildcard
+}
+
+/// Assets aren't versioned: clients must revalidate them every time,
+/// which mostly costs a 304.
+pub async fn asset_reply(request: &Request, content_type: &str, asset: &RwLock<Asset>) -> Response {
+    let asset = asset.read().await;
+    let (bytes, coding) = asset.negotiate(request);
+
+    let validators = Validators {
+        etag: asset.etag(coding),
+        modified: asset.modified,
+    };
+
+    let mut response = match validators.not_modified(request) {
+        true => Response::new("304 Not Modified"),
+        false => Response::new("200 OK").body(content_type, bytes.to_vec()),
+    };
+
+    if let Some(coding) = coding {
+        response = response.header("Content-Encoding", coding);
+    }
+
+    validators.apply(response, "no-cache").header("Vary", "Accept-Encoding")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::parser::{Method, Version};
+
+    fn request(accept_encoding: &str) -> Request {
+        Request {
+            method: Method::Get,
+            path: "/".into(),
+            query: None,
+            version: Version::Http11,
+            headers: vec![("Accept-Encoding".into(), accept_encoding.into())],
+        }
+    }
+
+    #[test]
+    fn etags_hash_every_source() {
+        let mut asset = Asset::new();
+        asset.append(b"first", SystemTime::UNIX_EPOCH);
+        asset.append(b"second", SystemTime::now());
+
+        let hash = to_hex(Sha256::digest(b"first\r\nsecond\r\n").into());
+        assert_eq!(asset.etag(None), format!("\"{hash}\""));
+        assert_eq!(asset.etag(Some("br")), format!("\"{hash}-br\""));
+        assert!(asset.modified > SystemTime::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn empty_assets_are_tagged() {
+        let asset = Asset::new();
+        let hash = to_hex(Sha256::digest(b"").into());
+        assert_eq!(asset.etag(Some("gzip")), format!("\"{hash}-gzip\""));
+    }
+
+    #[test]
+    fn encodings_are_negotiated() {
+        assert_eq!(quality("gzip, deflate, br", "br"), 1.0);
+        assert_eq!(quality("gzip;q=0.5, br;q=0", "br"), 0.0);
+        assert_eq!(quality("gzip;q=0.5", "GZIP"), 0.5);
+        assert_eq!(quality("*;q=0.3", "br"), 0.3);
+        assert_eq!(quality("identity", "gzip"), 0.0);
+
+        let mut asset = Asset::new();
+        asset.append(b"body { margin: 0 }", SystemTime::now());
+        asset.compress();
+
+        assert_eq!(asset.negotiate(&request("gzip, br")).1, Some("br"));
+        assert_eq!(asset.negotiate(&request("gzip")).1, Some("gzip"));
+        assert_eq!(asset.negotiate(&request("")).1, None);
+    }
+}
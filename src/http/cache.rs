@@ -0,0 +1,79 @@
+use httpdate::{fmt_http_date, parse_http_date};
+
+use super::parser::Request;
+use super::response::Response;
+
+use std::time::{SystemTime, Duration};
+
+/// Validators of a representation
+pub struct Validators {
+    /// Strong entity tag, quoted
+    pub etag: String,
+    pub modified: SystemTime,
+}
+
+impl Validators {
+    /// True if the client's copy is still valid (RFC 9110, 13.2.2)
+    pub fn not_modified(&self, request: &Request) -> bool {
+        // If-Modified-Since is ignored when If-None-Match is present
+        if let Some(tags) = request.header("If-None-Match") {
+            let mut tags = tags.split(',').map(str::trim);
+            // weak comparison: W/"x" matches "x"
+            return tags.any(|tag| tag == "*" || tag.trim_start_matches("W/") == self.etag);
+        }
+
+        let since = request.header("If-Modified-Since").and_then(|s| parse_http_date(s).ok());
+        match since {
+            // dates have a one-second precision
+            Some(since) => self.modified < since + Duration::from_secs(1),
+            None => false,
+        }
+    }
+
+    /// Adds validators and the cache policy to a response
+    pub fn apply(&self, response: Response, cache_control: &str) -> Response {
+        response
+            .header("ETag", &self.etag)
+            .header("Last-Modified", fmt_http_date(self.modified))
+            .header("Cache-Control", cache_control)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::parser::{Method, Version};
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: Method::Get,
+            path: "/".into(),
+            query: None,
+            version: Version::Http11,
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let validators = Validators {
+            etag: "\"abc\"".into(),
+            modified,
+        };
+
+        let date = fmt_http_date(modified);
+        let earlier = fmt_http_date(modified - Duration::from_secs(10));
+
+        assert!(validators.not_modified(&request(&[("If-None-Match", "\"x\", W/\"abc\"")])));
+        assert!(validators.not_modified(&request(&[("If-None-Match", "*")])));
+        assert!(!validators.not_modified(&request(&[("If-None-Match", "\"x\"")])));
+        assert!(validators.not_modified(&request(&[("If-Modified-Since", &date)])));
+        assert!(!validators.not_modified(&request(&[("If-Modified-Since", &earlier)])));
+        assert!(!validators.not_modified(&request(&[])));
+
+        // the tag wins over the date
+        let both = [("If-None-Match", "\"x\""), ("If-Modified-Since", date.as_str())];
+        assert!(!validators.not_modified(&request(&both)));
+    }
+}
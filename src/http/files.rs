@@ -0,0 +1,265 @@
+use futures_lite::AsyncReadExt;
+use async_fs::File;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{DATABASE, to_hex, from_hex};
+use crate::config::config;
+use crate::database::EntityId;
+use crate::database::entities::IndexInEntity;
+use crate::database::objects::{BucketId, UserId, File as BucketFile, now_stamp};
+
+use super::parser::Request;
+use super::response::Response;
+use super::cache::Validators;
+use super::mime;
+
+use std::sync::LazyLock;
+use std::time::SystemTime;
+
+/// Bytes read to guess the type of a file
+const SNIFF_LENGTH: usize = 8192;
+/// Validity of download links, in seconds
+const LINK_LIFETIME: u64 = 60 * 60;
+
+/// Signs download links; links don't survive a restart
+static LINK_KEY: LazyLock<[u8; 32]> = LazyLock::new(rand::random);
+
+#[derive(Debug, PartialEq, Eq)]
+enum Range {
+    Full,
+    /// First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+fn link_mac(
+    user_id: UserId,
+    bucket_id: BucketId,
+    index: IndexInEntity,
+    hash: &str,
+    expires: u64,
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&*LINK_KEY).unwrap();
+    mac.update(format!("{user_id}/{bucket_id}/{index}/{hash}/{expires}").as_bytes());
+    mac
+}
+
+/// Short-lived URL which lets `user_id` download a file of a bucket
+pub fn file_link(
+    user_id: UserId,
+    bucket_id: BucketId,
+    index: IndexInEntity,
+    file: &BucketFile,
+) -> String {
+    let hash = &file.sha256;
+    let expires = now_stamp() + LINK_LIFETIME;
+    let mac = link_mac(user_id, bucket_id, index, hash, expires);
+    let signature = to_hex(mac.finalize().into_bytes().into());
+
+    let name = percent_encode(&file.name);
+    let query = format!("user={user_id}&bucket={bucket_id}&index={index}&expires={expires}");
+    format!("/files/{hash}/{name}?{query}&sig={signature}")
+}
+
+/// Checks the signature of a link, and that its user still has
+/// access to a bucket which holds the file. Returns that file
+/// and the expiration of the link.
+async fn check_link(request: &Request, hash: &str) -> Option<(BucketFile, u64)> {
+    let param = |name| request.query_param(name);
+    let user_id: UserId = param("user")?.parse().ok()?;
+    let bucket_id: BucketId = param("bucket")?.parse().ok()?;
+    let index: IndexInEntity = param("index")?.parse().ok()?;
+    let expires: u64 = param("expires")?.parse().ok()?;
+    let signature = from_hex(param("sig")?).ok()?;
+
+    if expires < now_stamp() {
+        return None;
+    }
+
+    let mac = link_mac(user_id, bucket_id, index, hash, expires);
+    mac.verify_slice(&signature).ok()?;
+
+    let arc_user = DATABASE.users.find(user_id).await?;
+    arc_user.check_access_to(EntityId::Bucket(bucket_id), false).await.ok()?;
+
+    let arc_bucket = DATABASE.buckets.find(bucket_id).await?;
+    let bucket = arc_bucket.read().await;
+    let file = bucket.files.get(index as usize)?;
+    (file.sha256 == hash).then(|| (file.clone(), expires))
+}
+
+/// `disposition; filename="..."; filename*=UTF-8''...` (RFC 6266, RFC 5987)
+fn content_disposition(disposition: &str, file_name: &str) -> String {
+    let fallback: String = file_name.chars().map(|c| match c {
+        ' '..='~' if c != '"' && c != '\\' => c,
+        _ => '_',
+    }).collect();
+
+    let encoded = percent_encode(file_name);
+    format!("{disposition}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+/// Serves a bucket file, streamed from disk, with Range support.
+/// Files are named by their hash, which therefore is a strong ETag.
+pub async fn file_reply(request: &Request) -> Response {
+    let Some(file_name) = request.path.strip_prefix("/files/") else {
+        return Response::not_found();
+    };
+
+    let Some((hash, _dl_name)) = file_name.split_once('/') else {
+        return Response::not_found();
+    };
+
+    let Some((bucket_file, expires)) = check_link(request, hash).await else {
+        let body = b"Invalid or expired link".to_vec();
+        return Response::new("403 Forbidden").body("text/plain", body);
+    };
+
+    let file_path = config().file_path(hash);
+    let Ok(mut file) = File::open(&file_path).await else {
+        return Response::not_found();
+    };
+
+    let Ok(metadata) = file.metadata().await else {
+        return Response::not_found();
+    };
+
+    let size = metadata.len();
+    let validators = Validators {
+        etag: format!("\"{hash}\""),
+        modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+    };
+
+    // the content never changes, but access must be checked again
+    // when the link expires
+    let max_age = expires.saturating_sub(now_stamp());
+    let cache_control = format!("private, max-age={max_age}");
+
+    if validators.not_modified(request) {
+        return validators.apply(Response::new("304 Not Modified"), &cache_control);
+    }
+
+    let mut start = Vec::with_capacity(SNIFF_LENGTH);
+    let sniffing = (&mut file).take(SNIFF_LENGTH as u64).read_to_end(&mut start).await;
+    let content_type = match sniffing.ok().and_then(|_| infer::get(&start)) {
+        Some(ftype) => ftype.mime_type(),
+        None => mime::from_extension(&bucket_file.name).unwrap_or("application/octet-stream"),
+    };
+
+    // ?disposition=attachment to download instead of displaying
+    let disposition = match request.query_param("disposition") {
+        Some("attachment") => "attachment",
+        _ if mime::is_active(content_type) => "attachment",
+        _ => "inline",
+    };
+
+    let disposition = content_disposition(disposition, &bucket_file.name);
+    let content_type = mime::with_charset(content_type);
+
+    // a stale If-Range means that the whole file must be sent
+    let if_range_ok = request.header("If-Range").is_none_or(|tag| tag == validators.etag);
+    let range = match request.header("Range") {
+        Some(range) if if_range_ok => parse_range(range, size),
+        _ => Range::Full,
+    };
+
+    println!("Serving {file_path} as {:?}", bucket_file.name);
+
+    let response = match range {
+        Range::Full => Response::new("200 OK").file(&content_type, file, 0, size),
+        Range::Partial(first, last) => Response::new("206 Partial Content")
+            .header("Content-Range", format!("bytes {first}-{last}/{size}"))
+            .file(&content_type, file, first, last - first + 1),
+        Range::Unsatisfiable => Response::new("416 Range Not Satisfiable")
+            .header("Content-Range", format!("bytes */{size}")),
+    };
+
+    validators.apply(response, &cache_control)
+        .header("Accept-Ranges", "bytes")
+        .header("Content-Disposition", disposition)
+        .header("X-Content-Type-Options", "nosniff")
+}
+
+/// Parses a single byte range; multiple ranges aren't
+/// supported, the whole file is sent instead (RFC 9110, 14.2).
+fn parse_range(header: &str, size: u64) -> Range {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Range::Full;
+    };
+
+    if spec.contains(',') {
+        return Range::Full;
+    }
+
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return Range::Full;
+    };
+
+    let (first, last) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        // bytes=-500: the last 500 bytes
+        (Err(_), Ok(suffix)) if first.is_empty() => match suffix {
+            0 => return Range::Unsatisfiable,
+            _ => (size.saturating_sub(suffix), size.saturating_sub(1)),
+        },
+        (Ok(first), Err(_)) if last.is_empty() => (first, size.saturating_sub(1)),
+        (Ok(first), Ok(last)) if first <= last => (first, last.min(size.saturating_sub(1))),
+        _ => return Range::Full,
+    };
+
+    match first < size {
+        true => Range::Partial(first, last),
+        false => Range::Unsatisfiable,
+    }
+}
+
+/// Encodes everything but unreserved characters (RFC 3986, 2.3)
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+
+    for byte in text.bytes() {
+        match byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            true => encoded.push(byte as char),
+            false => encoded += &format!("%{byte:02X}"),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Range::Partial(0, 99));
+        assert_eq!(parse_range("bytes=500-", 1000), Range::Partial(500, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), Range::Partial(900, 999));
+        assert_eq!(parse_range(" bytes= 10-20 ", 1000), Range::Partial(10, 20));
+
+        // clamped to the end of the file
+        assert_eq!(parse_range("bytes=900-5000", 1000), Range::Partial(900, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), Range::Partial(0, 999));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Range::Unsatisfiable);
+
+        // ignored: the whole file is sent
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), Range::Full);
+        assert_eq!(parse_range("bytes=20-10", 1000), Range::Full);
+        assert_eq!(parse_range("items=0-1", 1000), Range::Full);
+        assert_eq!(parse_range("bytes=a-b", 1000), Range::Full);
+        assert_eq!(parse_range("bytes=5", 1000), Range::Full);
+    }
+
+    #[test]
+    fn file_names_are_encoded() {
+        assert_eq!(percent_encode("a b-é.txt"), "a%20b-%C3%A9.txt");
+        assert_eq!(
+            content_disposition("attachment", "ré\"sumé.pdf"),
+            "attachment; filename=\"r__sum_.pdf\"; filename*=UTF-8''r%C3%A9%22sum%C3%A9.pdf",
+        );
+    }
+}
@@ -0,0 +1,65 @@
+/// Types which are guessed from the extension of a file name,
+/// when its first bytes don't match a known signature.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("svg", "image/svg+xml"),
+    ("ics", "text/calendar"),
+    ("vcf", "text/vcard"),
+    ("log", "text/plain"),
+    ("ini", "text/plain"),
+    ("toml", "text/plain"),
+    ("yaml", "text/plain"),
+    ("yml", "text/plain"),
+    ("rs", "text/plain"),
+    ("py", "text/plain"),
+    ("c", "text/plain"),
+    ("h", "text/plain"),
+    ("sh", "text/plain"),
+    ("srt", "text/plain"),
+    ("vtt", "text/vtt"),
+    ("rtf", "application/rtf"),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+    ("doc", "application/msword"),
+    ("xls", "application/vnd.ms-excel"),
+    ("ppt", "application/vnd.ms-powerpoint"),
+];
+
+/// Types which could run scripts in our origin if displayed inline
+const ACTIVE: &[&str] = &[
+    "text/html",
+    "text/javascript",
+    "image/svg+xml",
+    "application/xml",
+    "application/xhtml+xml",
+];
+
+pub fn from_extension(file_name: &str) -> Option<&'static str> {
+    let (_, extension) = file_name.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    let mut table = EXTENSIONS.iter();
+    table.find(|(ext, _)| *ext == extension).map(|(_, mime)| *mime)
+}
+
+pub fn is_active(mime: &str) -> bool {
+    ACTIVE.contains(&mime)
+}
+
+/// Adds a charset to textual types
+pub fn with_charset(mime: &str) -> String {
+    match mime.starts_with("text/") || mime == "application/json" {
+        true => format!("{mime}; charset=utf-8"),
+        false => mime.to_string(),
+    }
+}
@@ -0,0 +1,198 @@
+use futures_lite::AsyncWriteExt;
+use async_net::{TcpListener, TcpStream};
+use async_channel::Sender;
+use async_io::Timer;
+use rustls::ServerConfig;
+
+use std::future::Future;
+use std::time::Duration;
+use std::sync::Arc;
+
+use crate::{deflate, session, shutdown, or};
+use crate::front::{INDEX_HTML, MAIN_JS, STYLE_CSS};
+use crate::executor::Task;
+use crate::config::config;
+use crate::tls::{self, Stream};
+
+use parser::{Connection, HttpError, Method, Request};
+use response::Response;
+use assets::asset_reply;
+
+mod mime;
+mod cache;
+mod files;
+mod assets;
+mod parser;
+mod response;
+
+pub use files::file_link;
+pub use assets::Asset;
+
+/// Idle persistent connections are closed after this delay
+const KEEP_ALIVE_SECONDS: u64 = 30;
+
+pub async fn listen(tx_tasks: Sender<Task>) {
+    let addr = &config().addr;
+    let tls = tls::server_config();
+
+    if let (Some(_), Some(redirect_addr)) = (&tls, &config().redirect_addr) {
+        println!("Redirecting {} to HTTPS", redirect_addr);
+        let listener = TcpListener::bind(redirect_addr).await.unwrap();
+        let redirect_task = accept_loop(listener, tx_tasks.clone(), redirect);
+        let _ = tx_tasks.send(redirect_task.into()).await;
+    }
+
+    println!("Listening on {}", addr);
+    let listener = TcpListener::bind(addr).await.unwrap();
+
+    accept_loop(listener, tx_tasks, move |tcp| {
+        process_connection(tcp, tls.clone())
+    }).await;
+}
+
+async fn accept_loop<F, T>(listener: TcpListener, tx_tasks: Sender<Task>, handler: F)
+where
+    F: Fn(TcpStream) -> T,
+    T: Future<Output = ()> + Send + 'static,
+{
+    loop {
+        let closing = async {
+            shutdown::closing().await;
+            None
+        };
+
+        let next_client = async { listener.accept().await.ok() };
+        let Some((stream, _addr)) = or(closing, next_client).await else {
+            println!("No longer accepting connections");
+            break;
+        };
+
+        let new_task = handler(stream);
+        if tx_tasks.send(new_task.into()).await.is_err() {
+            println!("Failed to schedule request handler");
+        }
+    }
+}
+
+/// Waits for the next request, unless the connection stays idle
+/// for too long or the server is going down.
+async fn next_request(conn: &mut Connection) -> Result<Request, HttpError> {
+    let give_up = async {
+        let timeout = Timer::after(Duration::from_secs(KEEP_ALIVE_SECONDS));
+        or(async { timeout.await; }, shutdown::closing()).await;
+        Err(HttpError::Closed)
+    };
+
+    or(conn.read_request(), give_up).await
+}
+
+/// Serves requests until the client or the server closes the connection
+async fn serve<F, T>(stream: Stream, handler: F)
+where
+    F: Fn(Connection, Request) -> T,
+    T: Future<Output = Option<Connection>>,
+{
+    let mut conn = Connection::new(stream);
+
+    loop {
+        let request = match next_request(&mut conn).await {
+            Ok(request) => request,
+            Err(error) => {
+                if let Some(status) = error.status() {
+                    println!("Invalid HTTP request: {}", status);
+                    let mut response = Response::new(status).body("text/plain", status.into());
+                    if let HttpError::MethodNotAllowed = error {
+                        response = response.header("Allow", "GET, HEAD");
+                    }
+
+                    let _ = response.send(&mut conn, None, false).await;
+                }
+
+                break;
+            },
+        };
+
+        // handlers consume the connection when they don't give it back
+        let Some(next_conn) = handler(conn, request).await else {
+            return;
+        };
+
+        conn = next_conn;
+    }
+
+    let _ = conn.stream.close().await;
+}
+
+async fn process_connection(tcp: TcpStream, tls: Option<Arc<ServerConfig>>) {
+    let Ok(stream) = Stream::new(tcp, tls.as_ref()) else {
+        println!("couldn't set up TLS");
+        return;
+    };
+
+    serve(stream, process_request).await;
+}
+
+async fn process_request(mut conn: Connection, request: Request) -> Option<Connection> {
+    println!("request: {}", request.path);
+    let keep_alive = request.keep_alive() && !shutdown::is_closing();
+
+    let response = match request.path.as_str() {
+        "/session" => return upgrade(conn, &request).await,
+        "/" => asset_reply(&request, "text/html", &INDEX_HTML).await,
+        "/main.js" => asset_reply(&request, "text/javascript", &MAIN_JS).await,
+        "/style.css" => asset_reply(&request, "text/css", &STYLE_CSS).await,
+        _ => files::file_reply(&request).await,
+    };
+
+    let result = response.send(&mut conn, Some(&request), keep_alive).await;
+    (result.is_ok() && keep_alive).then_some(conn)
+}
+
+/// Hands the connection over to a WebSocket session
+async fn upgrade(mut conn: Connection, request: &Request) -> Option<Connection> {
+    let ws_key = request.header("Sec-WebSocket-Key");
+    let is_upgrade = request.header_has("Upgrade", "websocket");
+    let headers = request.headers.iter();
+    let extensions = headers.filter(|(key, _)| key.eq_ignore_ascii_case("Sec-WebSocket-Extensions"));
+    let deflate = deflate::offered(extensions.map(|(_, value)| value.as_str()));
+
+    match (request.method, ws_key) {
+        (Method::Get, Some(ws_key)) if is_upgrade => session(conn.stream, ws_key, deflate).await,
+        _ => {
+            let body = b"Expected a WebSocket".to_vec();
+            let response = Response::new("400 Bad Request").body("text/plain", body);
+            let _ = response.send(&mut conn, Some(request), false).await;
+            let _ = conn.stream.close().await;
+        },
+    }
+
+    None
+}
+
+/// Sends plain HTTP clients to the HTTPS listener
+async fn redirect(tcp: TcpStream) {
+    serve(Stream::Plain(tcp), |mut conn, request| async move {
+        let keep_alive = request.keep_alive() && !shutdown::is_closing();
+
+        let response = match request.header("Host") {
+            Some(host) => {
+                let hostname = host.rsplit_once(':').map_or(host, |(hostname, _port)| hostname);
+                let path = match &request.query {
+                    Some(query) => format!("{}?{}", request.path, query),
+                    None => request.path.clone(),
+                };
+
+                let location = match config().addr.rsplit_once(':') {
+                    Some((_, port)) if port != "443" => format!("https://{hostname}:{port}{path}"),
+                    _ => format!("https://{hostname}{path}"),
+                };
+
+                Response::new("301 Moved Permanently").header("Location", location)
+            },
+            None => Response::new("400 Bad Request"),
+        };
+
+        let result = response.send(&mut conn, Some(&request), keep_alive).await;
+        (result.is_ok() && keep_alive).then_some(conn)
+    }).await;
+}
@@ -0,0 +1,294 @@
+use futures_lite::AsyncReadExt;
+
+use crate::tls::Stream;
+
+use std::str::from_utf8;
+
+/// Longest accepted request line (414 beyond)
+const MAX_REQUEST_LINE: usize = 8 * 1024;
+/// Longest accepted request head, headers included (400 beyond)
+const MAX_HEAD_SIZE: usize = 32 * 1024;
+/// Largest body which is read (and discarded) to keep the connection usable
+const MAX_DISCARDED_BODY: usize = 64 * 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: Version,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    /// The connection was closed (or failed) between two requests
+    Closed,
+    BadRequest,
+    MethodNotAllowed,
+    UriTooLong,
+}
+
+/// Reads requests from a connection, keeping bytes of the next one
+pub struct Connection {
+    pub stream: Stream,
+    buffer: Vec<u8>,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Http10 => "HTTP/1.0",
+            Self::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl HttpError {
+    /// Status line of the error response, if one should be sent
+    pub fn status(&self) -> Option<&'static str> {
+        match self {
+            Self::Closed => None,
+            Self::BadRequest => Some("400 Bad Request"),
+            Self::MethodNotAllowed => Some("405 Method Not Allowed"),
+            Self::UriTooLong => Some("414 URI Too Long"),
+        }
+    }
+}
+
+impl Request {
+    /// Value of the first header named `name` (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let mut headers = self.headers.iter();
+        let (_, value) = headers.find(|(key, _)| key.eq_ignore_ascii_case(name))?;
+        Some(value)
+    }
+
+    /// True if `name` is a comma-separated list containing `token`
+    pub fn header_has(&self, name: &str, token: &str) -> bool {
+        let values = self.headers.iter().filter(|(key, _)| key.eq_ignore_ascii_case(name));
+        let mut tokens = values.flat_map(|(_, value)| value.split(','));
+        tokens.any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Value of a query string parameter, as is (not decoded)
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        let mut pairs = self.query.as_deref()?.split('&');
+        let pair = pairs.find(|pair| pair.split_once('=').is_some_and(|(key, _)| key == name))?;
+        pair.split_once('=').map(|(_, value)| value)
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http10 => self.header_has("Connection", "keep-alive"),
+            Version::Http11 => !self.header_has("Connection", "close"),
+        }
+    }
+
+    fn content_length(&self) -> Result<usize, HttpError> {
+        match self.header("Content-Length") {
+            Some(length) => length.parse().map_err(|_| HttpError::BadRequest),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Connection {
+    pub fn new(stream: Stream) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Reads more bytes into the buffer; returns false at the end of the stream
+    async fn fill(&mut self) -> bool {
+        let mut chunk = [0; 4096];
+        match self.stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => false,
+            Ok(length) => {
+                self.buffer.extend_from_slice(&chunk[..length]);
+                true
+            },
+        }
+    }
+
+    pub async fn read_request(&mut self) -> Result<Request, HttpError> {
+        let head_len = loop {
+            if let Some(i) = find(&self.buffer, b"\r\n\r\n") {
+                break i + 4;
+            }
+
+            let line_complete = find(&self.buffer, b"\r\n").is_some();
+            if !line_complete && self.buffer.len() > MAX_REQUEST_LINE {
+                return Err(HttpError::UriTooLong);
+            }
+
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                return Err(HttpError::BadRequest);
+            }
+
+            if !self.fill().await {
+                return match self.buffer.is_empty() {
+                    true => Err(HttpError::Closed),
+                    false => Err(HttpError::BadRequest),
+                };
+            }
+        };
+
+        let head: Vec<u8> = self.buffer.drain(..head_len).collect();
+        let head = from_utf8(&head).map_err(|_| HttpError::BadRequest)?;
+        let request = parse_head(head)?;
+
+        if request.header("Transfer-Encoding").is_some() {
+            return Err(HttpError::BadRequest);
+        }
+
+        // no route takes a body, but it mustn't be read as the next request
+        let body_len = request.content_length()?;
+        if body_len > MAX_DISCARDED_BODY {
+            return Err(HttpError::BadRequest);
+        }
+
+        while self.buffer.len() < body_len {
+            if !self.fill().await {
+                return Err(HttpError::Closed);
+            }
+        }
+
+        self.buffer.drain(..body_len);
+        Ok(request)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn parse_head(head: &str) -> Result<Request, HttpError> {
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().ok_or(HttpError::BadRequest)?;
+
+    if request_line.len() > MAX_REQUEST_LINE {
+        return Err(HttpError::UriTooLong);
+    }
+
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(path), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(HttpError::BadRequest);
+    };
+
+    let version = match version {
+        "HTTP/1.0" => Version::Http10,
+        "HTTP/1.1" => Version::Http11,
+        _ => return Err(HttpError::BadRequest),
+    };
+
+    if !path.starts_with('/') {
+        return Err(HttpError::BadRequest);
+    }
+
+    let method = match method {
+        "GET" => Method::Get,
+        "HEAD" => Method::Head,
+        _ if method.bytes().all(|b| b.is_ascii_uppercase()) => return Err(HttpError::MethodNotAllowed),
+        _ => return Err(HttpError::BadRequest),
+    };
+
+    let mut headers = Vec::new();
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (key, value) = line.split_once(':').ok_or(HttpError::BadRequest)?;
+
+        // whitespace before the colon is forbidden (RFC 9112, 5.1)
+        if key.is_empty() || key.ends_with([' ', '\t']) {
+            return Err(HttpError::BadRequest);
+        }
+
+        headers.push((key.to_string(), value.trim().to_string()));
+    }
+
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (path, None),
+    };
+
+    Ok(Request {
+        method,
+        path: path.to_string(),
+        query,
+        version,
+        headers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(head: &str) -> Result<Request, HttpError> {
+        parse_head(&head.replace('\n', "\r\n"))
+    }
+
+    #[test]
+    fn requests_are_parsed() {
+        let request = parse("GET /files/x?bucket=3&sig=ab HTTP/1.1\nHost: kolab\nX-Empty:\nAccept:  text/html \n\n").unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.path, "/files/x");
+        assert_eq!(request.query_param("bucket"), Some("3"));
+        assert_eq!(request.query_param("sig"), Some("ab"));
+        assert_eq!(request.query_param("missing"), None);
+        assert_eq!(request.header("accept"), Some("text/html"));
+        assert_eq!(request.header("X-Empty"), Some(""));
+
+        let request = parse("HEAD / HTTP/1.0\n\n").unwrap();
+        assert_eq!(request.method, Method::Head);
+        assert_eq!(request.query, None);
+    }
+
+    #[test]
+    fn bad_requests_are_rejected() {
+        let status = |head: &str| parse(head).err().and_then(|e| e.status());
+        assert_eq!(status("POST / HTTP/1.1\n\n"), Some("405 Method Not Allowed"));
+        assert_eq!(status("get / HTTP/1.1\n\n"), Some("400 Bad Request"));
+        assert_eq!(status("GET / HTTP/2\n\n"), Some("400 Bad Request"));
+        assert_eq!(status("GET http://x/ HTTP/1.1\n\n"), Some("400 Bad Request"));
+        assert_eq!(status("GET  / HTTP/1.1\n\n"), Some("400 Bad Request"));
+        assert_eq!(status("GET / HTTP/1.1\nHost : x\n\n"), Some("400 Bad Request"));
+        assert_eq!(status("GET / HTTP/1.1\nno colon\n\n"), Some("400 Bad Request"));
+
+        let long_path = format!("GET /{} HTTP/1.1\n\n", "a".repeat(MAX_REQUEST_LINE));
+        assert_eq!(status(&long_path), Some("414 URI Too Long"));
+    }
+
+    #[test]
+    fn connections_are_kept_alive() {
+        let keep_alive = |head: &str| parse(head).unwrap().keep_alive();
+        assert!(keep_alive("GET / HTTP/1.1\n\n"));
+        assert!(!keep_alive("GET / HTTP/1.1\nConnection: Upgrade, close\n\n"));
+        assert!(!keep_alive("GET / HTTP/1.0\n\n"));
+        assert!(keep_alive("GET / HTTP/1.0\nConnection: Keep-Alive\n\n"));
+    }
+
+    #[test]
+    fn content_lengths_are_checked() {
+        let length = |head: &str| parse(head).unwrap().content_length().ok();
+        assert_eq!(length("GET / HTTP/1.1\n\n"), Some(0));
+        assert_eq!(length("GET / HTTP/1.1\nContent-Length: 12\n\n"), Some(12));
+        assert_eq!(length("GET / HTTP/1.1\nContent-Length: -1\n\n"), None);
+    }
+}
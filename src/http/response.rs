@@ -0,0 +1,105 @@
+use futures_lite::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use futures_lite::io::copy;
+use async_fs::File;
+
+use super::parser::{Connection, Method, Request, Version};
+
+use std::io::{self, SeekFrom};
+
+pub struct Response {
+    pub status: &'static str,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Body,
+}
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// Streamed from disk: file, offset, length
+    File(File, u64, u64),
+}
+
+impl Body {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Bytes(bytes) => bytes.len() as u64,
+            Self::File(_, _, length) => *length,
+        }
+    }
+}
+
+impl Response {
+    pub fn new(status: &'static str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    pub fn body(self, content_type: &str, body: Vec<u8>) -> Self {
+        let mut this = self.header("Content-Type", content_type);
+        this.body = Body::Bytes(body);
+        this
+    }
+
+    /// Streams `length` bytes of `file`, starting at `offset`
+    pub fn file(self, content_type: &str, file: File, offset: u64, length: u64) -> Self {
+        let mut this = self.header("Content-Type", content_type);
+        this.body = Body::File(file, offset, length);
+        this
+    }
+
+    pub fn not_found() -> Self {
+        Self::new("404 Not Found").body("text/html", b"Not Found!".to_vec())
+    }
+
+    /// Writes the response; `request` is None if it couldn't be parsed
+    pub async fn send(
+        self,
+        conn: &mut Connection,
+        request: Option<&Request>,
+        keep_alive: bool,
+    ) -> io::Result<()> {
+        let version = request.map_or(Version::Http11, |r| r.version).as_str();
+        let head_only = request.is_some_and(|r| r.method == Method::Head);
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+
+        let mut head = format!("{version} {}\r\n", self.status);
+        // a 304 has no body, and its length would be misleading
+        if self.status != "304 Not Modified" {
+            head += &format!("Content-Length: {}\r\n", self.body.len());
+        }
+
+        head += &format!("Connection: {connection}\r\n");
+        head += "Server: Kolab\r\n";
+
+        for (name, value) in &self.headers {
+            head += &format!("{name}: {value}\r\n");
+        }
+
+        head += "\r\n";
+
+        conn.stream.write_all(head.as_bytes()).await?;
+
+        match self.body {
+            _ if head_only => (),
+            Body::Bytes(bytes) => conn.stream.write_all(&bytes).await?,
+            Body::File(mut file, offset, length) => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                let copied = copy(file.take(length), &mut conn.stream).await?;
+
+                if copied < length {
+                    // the client would wait for the missing bytes forever
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            },
+        }
+
+        conn.stream.flush().await
+    }
+}
@@ -0,0 +1,263 @@
+use futures_lite::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use futures_lite::future::or;
+use async_net::TcpStream;
+use async_lock::RwLock;
+use async_fs::OpenOptions;
+use async_io::Timer;
+use rustls::ClientConfig;
+
+use crate::database::objects::Email;
+use crate::tls::{self, Stream};
+use crate::StringifyError;
+use crate::config::config;
+
+use std::future::Future;
+use std::time::Duration;
+use std::sync::Arc;
+use std::pin::Pin;
+
+/// Used when `smtp_ca_file` isn't set
+const SYSTEM_CA_FILE: &str = "/etc/ssl/certs/ca-certificates.crt";
+/// Maximum duration of each step of an SMTP transaction
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type MailFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+static MAILER: RwLock<Option<Box<dyn MailTransport>>> = RwLock::new(None);
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: Email,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait MailTransport: Send + Sync {
+    fn send<'a>(&'a self, mail: &'a Mail) -> MailFuture<'a>;
+}
+
+/// Relays mails to an SMTP server, always over TLS
+pub struct SmtpTransport {
+    /// host:port
+    pub server: String,
+    pub from: Email,
+    pub credentials: Option<(String, String)>,
+    /// STARTTLS is used otherwise
+    pub implicit_tls: bool,
+    pub tls: Arc<ClientConfig>,
+}
+
+/// Appends mails to a file, or prints them if there's no path.
+/// Meant for local testing.
+pub struct FileTransport {
+    pub path: Option<String>,
+}
+
+/// Selects a transport based on the mail configuration:
+/// SMTP if a server is set, otherwise a file (or stdout).
+pub async fn init_mailer() {
+    let mail = &config().mail;
+
+    let transport: Box<dyn MailTransport> = match &mail.smtp_server {
+        Some(server) => Box::new(SmtpTransport {
+            server: server.clone(),
+            from: mail.from.clone(),
+            credentials: mail.smtp_user.clone().zip(mail.smtp_password.clone()),
+            implicit_tls: mail.smtp_implicit_tls,
+            tls: tls::client_config(mail.smtp_ca_file.as_deref().unwrap_or(SYSTEM_CA_FILE)),
+        }),
+        None => Box::new(FileTransport {
+            path: mail.file.clone(),
+        }),
+    };
+
+    let mut writer = MAILER.write().await;
+    let _ = writer.insert(transport);
+}
+
+pub async fn send_mail(mail: &Mail) -> Result<(), String> {
+    let reader = MAILER.read().await;
+    let transport = reader.as_ref().ok_or("mailer not initialized")?;
+    transport.send(mail).await
+}
+
+impl Mail {
+    fn format(&self, from: &str) -> String {
+        let Self { to, subject, body } = self;
+        format!("From: {from}\r\nTo: {to}\r\nSubject: {subject}\r\n\r\n{body}\r\n")
+    }
+}
+
+impl MailTransport for FileTransport {
+    fn send<'a>(&'a self, mail: &'a Mail) -> MailFuture<'a> {
+        Box::pin(async move {
+            let text = mail.format("kolab");
+
+            let Some(path) = &self.path else {
+                println!("MAIL:\n{}", text);
+                return Ok(());
+            };
+
+            let mut options = OpenOptions::new();
+            options.append(true).create(true);
+
+            let mut file = options.open(path).await.fmt_err("mail file")?;
+            file.write_all(text.as_bytes()).await.fmt_err("mail file")?;
+            file.write_all(b"\r\n").await.fmt_err("mail file")?;
+            // async_fs buffers writes until flushed
+            file.flush().await.fmt_err("mail file")
+        })
+    }
+}
+
+impl MailTransport for SmtpTransport {
+    fn send<'a>(&'a self, mail: &'a Mail) -> MailFuture<'a> {
+        Box::pin(self.transaction(mail))
+    }
+}
+
+impl SmtpTransport {
+    async fn transaction(&self, mail: &Mail) -> Result<(), String> {
+        let mut smtp = timeout(self.connect()).await?;
+        command(&mut smtp, "EHLO kolab", 250).await?;
+
+        if let Some((user, password)) = &self.credentials {
+            let auth = base64(format!("\0{user}\0{password}").as_bytes());
+            command(&mut smtp, &format!("AUTH PLAIN {auth}"), 235).await?;
+        }
+
+        command(&mut smtp, &format!("MAIL FROM:<{}>", self.from), 250).await?;
+        command(&mut smtp, &format!("RCPT TO:<{}>", mail.to), 250).await?;
+        command(&mut smtp, "DATA", 354).await?;
+
+        // dot-stuffing
+        let data = mail.format(&self.from).replace("\r\n.", "\r\n..");
+        command(&mut smtp, &format!("{data}\r\n."), 250).await?;
+        command(&mut smtp, "QUIT", 221).await
+    }
+
+    /// Opens an encrypted session, up to the greeting
+    async fn connect(&self) -> Result<BufReader<Stream>, String> {
+        let host = self.server.rsplit_once(':').map_or(&*self.server, |(host, _port)| host);
+        let tcp = TcpStream::connect(&self.server).await.fmt_err("smtp connect")?;
+
+        if self.implicit_tls {
+            let stream = Stream::client(tcp, &self.tls, host).fmt_err("smtp tls")?;
+            let mut smtp = BufReader::new(stream);
+            expect_reply(&mut smtp, 220).await?;
+            return Ok(smtp);
+        }
+
+        let mut smtp = BufReader::new(Stream::Plain(tcp));
+        expect_reply(&mut smtp, 220).await?;
+        command(&mut smtp, "EHLO kolab", 250).await?;
+        command(&mut smtp, "STARTTLS", 220).await?;
+
+        // the server waits for the handshake, nothing is buffered
+        let Stream::Plain(tcp) = smtp.into_inner() else {
+            unreachable!()
+        };
+
+        let stream = Stream::client(tcp, &self.tls, host).fmt_err("smtp tls")?;
+        Ok(BufReader::new(stream))
+    }
+}
+
+/// Fails if `step` takes longer than `SMTP_TIMEOUT`
+async fn timeout<T>(step: impl Future<Output = Result<T, String>>) -> Result<T, String> {
+    let expired = async {
+        Timer::after(SMTP_TIMEOUT).await;
+        Err("[smtp] timed out".to_string())
+    };
+
+    or(step, expired).await
+}
+
+async fn command(smtp: &mut BufReader<Stream>, line: &str, code: u16) -> Result<(), String> {
+    timeout(async {
+        let stream = smtp.get_mut();
+        stream.write_all(line.as_bytes()).await.fmt_err("smtp write")?;
+        stream.write_all(b"\r\n").await.fmt_err("smtp write")?;
+        stream.flush().await.fmt_err("smtp write")?;
+        expect_reply(smtp, code).await
+    }).await
+}
+
+/// Reads a (possibly multi-line) reply and checks its code
+async fn expect_reply(smtp: &mut BufReader<Stream>, code: u16) -> Result<(), String> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        smtp.read_line(&mut line).await.fmt_err("smtp read")?;
+
+        let (reply_code, is_last) = match line.get(..4) {
+            Some(start) => (start[..3].parse::<u16>().ok(), !start.ends_with('-')),
+            None => (None, true),
+        };
+
+        if reply_code != Some(code) {
+            return Err(format!("[smtp] unexpected reply: {:?}", line.trim_end()));
+        }
+
+        if is_last {
+            return Ok(());
+        }
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => output.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char),
+                false => output.push('='),
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_vectors() {
+        // RFC 4648, section 10
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+
+        for (input, output) in vectors {
+            assert_eq!(base64(input.as_bytes()), output);
+        }
+
+        assert_eq!(base64(b"\0user\0pass"), "AHVzZXIAcGFzcw==");
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+    }
+
+    #[test]
+    fn mail_format() {
+        let mail = Mail {
+            to: "someone@example.com".into(),
+            subject: "Hi".into(),
+            body: "Hello".into(),
+        };
+
+        let text = mail.format("kolab@example.com");
+        assert_eq!(text, "From: kolab@example.com\r\nTo: someone@example.com\r\nSubject: Hi\r\n\r\nHello\r\n");
+    }
+}
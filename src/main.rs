@@ -1,18 +1,26 @@
 use async_tungstenite::tungstenite::protocol::Message;
 use async_channel::{Sender, Receiver, unbounded};
 use futures_lite::future::{block_on, or};
-use async_fs::{read, read_to_string};
-use async_tungstenite::accept_async;
+use async_fs::read_to_string;
+use async_tungstenite::tungstenite::handshake::derive_accept_key;
+use async_tungstenite::tungstenite::protocol::Role;
+use async_tungstenite::WebSocketStream;
 use futures_util::sink::SinkExt;
-use futures_lite::{StreamExt};
-use sha2::{Digest, Sha256};
-use async_net::TcpStream;
+use futures_lite::{StreamExt, AsyncWriteExt};
 use async_lock::RwLock;
 
-type WebSocket = async_tungstenite::WebSocketStream<TcpStream>;
+type WebSocket = WebSocketStream<deflate::Stream<tls::Stream>>;
 
 mod http;
+mod tls;
+mod deflate;
+mod front;
+mod mail;
 mod backup;
+mod config;
+mod password;
+mod totp;
+mod shutdown;
 mod session;
 mod database;
 mod executor;
@@ -21,62 +29,57 @@ mod serde_utils;
 use session::Session;
 use database::Database;
 use executor::{spawn_runner, runner};
+use config::{config, SNAPSHOT};
+use backup::BackupSignal;
 
 static DATABASE: Database = Database::init();
 static FULL_DB_ACCESS: RwLock<()> = RwLock::new(());
-static TX_BACKUP_SIGNAL: RwLock<Option<Sender<()>>> = RwLock::new(None);
-
-static INDEX_HTML: RwLock<Vec<u8>> = RwLock::new(Vec::new());
-static STYLE_CSS: RwLock<Vec<u8>> = RwLock::new(Vec::new());
-static MAIN_JS: RwLock<Vec<u8>> = RwLock::new(Vec::new());
+static TX_BACKUP_SIGNAL: RwLock<Option<Sender<BackupSignal>>> = RwLock::new(None);
 
 async fn trigger_backup() {
     let reader = TX_BACKUP_SIGNAL.read().await;
-    let _ = reader.as_ref().unwrap().send(()).await;
+    let _ = reader.as_ref().unwrap().send(BackupSignal::Exit).await;
 }
 
-fn crypto_hash(salt: Option<[u8; 32]>, mut string: String) -> String {
-    let salt = salt.as_ref().map(|s| s.as_slice()).unwrap_or(b"");
-    let mut hasher = Sha256::new();
-
-    hasher.update(salt);
-    hasher.update(&string);
-
-    let hash = to_hex(hasher.finalize().into());
-
-    // erase from ram
-    // the whitespace will move to the left, erasing every char
-    string.push(' ');
-    while string.len() > 0 {
-        string.remove(0);
-    }
-
-    hash
+/// Schedules a snapshot without exiting; it can't begin
+/// before the caller releases its `FULL_DB_ACCESS` guard.
+async fn backup_now() {
+    let reader = TX_BACKUP_SIGNAL.read().await;
+    let _ = reader.as_ref().unwrap().send(BackupSignal::Now).await;
 }
 
-async fn session(stream: TcpStream) {
+async fn session(mut stream: tls::Stream, ws_key: &str, deflate: bool) {
     let Ok(address) = stream.peer_addr() else {
         println!("Failed to read peer address");
         return;
     };
 
-    let Ok(ws_stream) = accept_async(stream).await else {
+    let accept_key = derive_accept_key(ws_key.as_bytes());
+    let extensions = match deflate {
+        true => format!("Sec-WebSocket-Extensions: {}\r\n", deflate::RESPONSE),
+        false => String::new(),
+    };
+
+    let reply = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+        Connection: Upgrade\r\nSec-WebSocket-Accept: {accept_key}\r\n{extensions}\r\n"
+    );
+
+    if stream.write_all(reply.as_bytes()).await.is_err() || stream.flush().await.is_err() {
         println!("Failed to negociate WS session");
         return;
-    };
+    }
 
-    Session::run(address, ws_stream).await;
-    println!("End Of Session");
-}
+    let stream = deflate::Stream::new(stream, deflate);
+    let ws_stream = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
 
-async fn init_resource(staticc: &RwLock<Vec<u8>>, path: &str) {
-    let mut dst = staticc.write().await;
-    let src = read(path).await.unwrap();
-    dst.extend_from_slice(&src);
-    dst.extend_from_slice(b"\r\n");
+    Session::run(address, ws_stream, deflate).await;
+    println!("End Of Session");
 }
 
 pub fn main() {
+    config::init_config();
+
     let backup_task = {
         let (tx_signal, rx_signal) = unbounded();
 
@@ -89,37 +92,33 @@ pub fn main() {
     };
 
     let init_resources = async move {
-        init_resource(&INDEX_HTML, "front/index.html").await;
-        init_resource(&STYLE_CSS, "front/style.css").await;
-
-        // gather all js
-        init_resource(&MAIN_JS, "front/js/common.js").await;
-        init_resource(&MAIN_JS, "front/js/socket.js").await;
-        init_resource(&MAIN_JS, "front/js/entities.js").await;
-        init_resource(&MAIN_JS, "front/js/user.js").await;
-        init_resource(&MAIN_JS, "front/js/conv.js").await;
-        init_resource(&MAIN_JS, "front/js/doc.js").await;
-        init_resource(&MAIN_JS, "front/js/bucket.js").await;
-        init_resource(&MAIN_JS, "front/js/context-menu.js").await;
-        init_resource(&MAIN_JS, "front/js/emojis.js").await;
-        init_resource(&MAIN_JS, "front/js/init.js").await;
-
-        let db_json = read_to_string("database.json").await.unwrap();
-        DATABASE.load_from_json(&db_json).await;
+        front::load_assets().await.expect("failed to load the front-end");
+
+        mail::init_mailer().await;
+
+        let db_json = read_to_string(config().database_path(SNAPSHOT)).await.unwrap();
+        DATABASE.load_from_json(&db_json).await
     };
 
-    block_on(init_resources);
+    let journal_seq = block_on(init_resources);
+    let journal_task = block_on(database::journal::init(journal_seq));
 
     let (tx_tasks, rx_tasks) = unbounded();
     let http_task = http::listen(tx_tasks.clone());
 
     tx_tasks.try_send(http_task.into()).unwrap();
     tx_tasks.try_send(backup_task.into()).unwrap();
+    tx_tasks.try_send(journal_task.into()).unwrap();
+    tx_tasks.try_send(shutdown::shutdown_task().into()).unwrap();
 
-    // spawn 3 threads to have 4 threads total
-    spawn_runner(&rx_tasks);
-    spawn_runner(&rx_tasks);
-    spawn_runner(&rx_tasks);
+    if config().watch_front {
+        tx_tasks.try_send(front::watch_task().into()).unwrap();
+    }
+
+    // the main thread is a runner too
+    for _ in 1..config().threads {
+        spawn_runner(&rx_tasks);
+    }
 
     runner(rx_tasks);
 }
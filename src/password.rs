@@ -0,0 +1,160 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::{Argon2, Algorithm, Version, Params};
+use blocking::unblock;
+use sha2::{Digest, Sha256};
+
+use crate::{to_hex, from_hex};
+
+use std::sync::LazyLock;
+
+/// Same as the front-end
+const MIN_LENGTH: usize = 4;
+/// Longer passwords only make hashing slower
+const MAX_LENGTH: usize = 1024;
+
+/// Checked for unknown users, so that they take as long as others
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2().hash_password(b"", &salt).expect("argon2 failure").to_string()
+});
+
+/// Outcome of a password check
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Check {
+    Wrong,
+    Valid,
+    /// Valid, but stored in an older format: should be hashed again
+    Outdated,
+}
+
+/// Argon2id, with the parameters recommended by OWASP
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default())
+}
+
+/// Checks a new password against the password policy
+pub fn check_policy(password: &str) -> Result<(), &'static str> {
+    match password.chars().count() {
+        n if n < MIN_LENGTH => Err("Password must be at least 4 characters long"),
+        _ if password.len() > MAX_LENGTH => Err("Password too long"),
+        _ => Ok(()),
+    }
+}
+
+/// Hashes a password into a PHC string (`$argon2id$v=19$...`),
+/// which includes the salt and the parameters.
+pub async fn hash(password: String) -> String {
+    unblock(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = argon2().hash_password(password.as_bytes(), &salt);
+        erase(password);
+        hash.expect("argon2 failure").to_string()
+    }).await
+}
+
+/// Checks a password against a stored hash. Hashes which aren't
+/// PHC strings are hex SHA-256 digests of `legacy_salt` + password.
+pub async fn verify(password: &str, stored: &str, legacy_salt: &str) -> Check {
+    let password = password.to_string();
+    let stored = stored.to_string();
+    let legacy_salt = legacy_salt.to_string();
+
+    unblock(move || {
+        let check = match PasswordHash::new(&stored) {
+            Ok(phc) => verify_phc(&password, &phc),
+            Err(_) => verify_legacy(&password, &stored, &legacy_salt),
+        };
+
+        erase(password);
+        check
+    }).await
+}
+
+/// Spends as much time as `verify` for a user who doesn't exist
+pub async fn verify_dummy(password: &str) {
+    let stored = unblock(|| DUMMY_HASH.as_str()).await;
+    verify(password, stored, "").await;
+}
+
+fn verify_phc(password: &str, phc: &PasswordHash) -> Check {
+    if argon2().verify_password(password.as_bytes(), phc).is_err() {
+        return Check::Wrong;
+    }
+
+    let current = Params::default();
+    let same_params = Params::try_from(phc).is_ok_and(|params| {
+        let cost = (params.m_cost(), params.t_cost(), params.p_cost());
+        cost == (current.m_cost(), current.t_cost(), current.p_cost())
+    });
+
+    match phc.algorithm == Algorithm::Argon2id.ident() && same_params {
+        true => Check::Valid,
+        false => Check::Outdated,
+    }
+}
+
+fn verify_legacy(password: &str, stored: &str, salt: &str) -> Check {
+    let salt = from_hex(salt).ok();
+    let salt = salt.as_ref().map(|s| s.as_slice()).unwrap_or(b"");
+
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(password);
+
+    match to_hex(hasher.finalize().into()) == stored {
+        true => Check::Outdated,
+        false => Check::Wrong,
+    }
+}
+
+/// Overwrites a password in memory before freeing it
+fn erase(mut string: String) {
+    // SAFETY: zeroes are valid UTF-8
+    unsafe { string.as_bytes_mut().fill(0) };
+    std::hint::black_box(string);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_policy() {
+        assert!(check_policy("abc").is_err());
+        assert!(check_policy("abcd").is_ok());
+        // characters are counted, not bytes
+        assert!(check_policy("ééé").is_err());
+        assert!(check_policy(&"a".repeat(MAX_LENGTH)).is_ok());
+        assert!(check_policy(&"a".repeat(MAX_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn legacy_hashes_are_outdated() {
+        let salt = to_hex([7; 32]);
+        let mut hasher = Sha256::new();
+        hasher.update([7; 32]);
+        hasher.update("hunter2");
+        let stored = to_hex(hasher.finalize().into());
+
+        assert_eq!(verify_legacy("hunter2", &stored, &salt), Check::Outdated);
+        assert_eq!(verify_legacy("hunter3", &stored, &salt), Check::Wrong);
+        assert_eq!(verify_legacy("hunter2", &stored, ""), Check::Wrong);
+
+        // the oldest hashes weren't salted
+        let unsalted = to_hex(Sha256::digest("hunter2").into());
+        assert_eq!(verify_legacy("hunter2", &unsalted, ""), Check::Outdated);
+        assert_eq!(verify_legacy("hunter2", &unsalted, "not hex"), Check::Outdated);
+    }
+
+    #[test]
+    fn hashes_are_verified() {
+        futures_lite::future::block_on(async {
+            let stored = hash("hunter2".into()).await;
+            assert!(stored.starts_with("$argon2id$"));
+            assert_eq!(verify("hunter2", &stored, "").await, Check::Valid);
+            assert_eq!(verify("hunter3", &stored, "").await, Check::Wrong);
+            assert_eq!(verify("", &DUMMY_HASH, "").await, Check::Valid);
+        });
+    }
+}
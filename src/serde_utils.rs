@@ -20,6 +20,12 @@ impl<T: Debug> SerdeRwLock<T> {
     }
 }
 
+impl<T: Debug + Default> Default for SerdeRwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
 impl<T: Debug> Deref for SerdeRwLock<T> {
     type Target = RwLock<T>;
 
@@ -1,40 +1,179 @@
+use async_channel::Sender;
 use litemap::LiteMap;
 
 use crate::{
     database::{
         EntityId,
         update::{Update, UpdateType},
-        objects::{Token, UserData, Email, Username, UserId, AssociatedImage},
-        entities::{Revision, EntityAccess, EntityData},
+        objects::{
+            Token, UserData, Email, Username, UserId, AssociatedImage,
+            TokenId, TokenRecord, ResetCode, Stamp, User, TwoFactor,
+        },
+        entities::{Revision, EntityAccess, EntityData, Entity},
+        journal::{self, JournalEntry, save_user, save_access, save_tokens},
     }
 };
 
-use crate::{DATABASE, crypto_hash, from_hex, to_hex, trigger_backup};
+use crate::{DATABASE, shutdown, executor};
+use crate::serde_utils::SerdeRwLock as RwLock;
+use crate::password::{self, Check};
+use crate::totp;
+use crate::config::config;
+use super::challenge::{PendingChallenge, valid_email};
+use super::throttle::{self, Key};
 use super::requests::{ChallengeTarget, Code, Invite};
 use super::replies::{Reply, ReplyData};
 use super::{Session, ErrMsg};
 
 use std::sync::Arc;
-use std::mem::drop;
+use std::mem::{drop, replace};
 use std::iter::once;
 
+/// Device labels are cut to this many characters
+const MAX_TOKEN_LABEL: usize = 64;
+/// Reset codes issued by admins expire after a day
+const RESET_CODE_LIFETIME: Stamp = 24 * 60 * 60;
+
+/// Tells sessions opened with revoked tokens to close
+pub(super) async fn close_sessions(user_id: UserId, sessions: Vec<Sender<Arc<Update>>>) {
+    let update = Update::new(UpdateType::TokenRevoked, EntityId::User(user_id), 0, 0, &());
+    let update = Arc::new(update);
+
+    for tx_update in sessions {
+        let _ = tx_update.send(update.clone()).await;
+    }
+}
+
+/// Replaces a password and revokes every token except `keep`
+async fn set_password(arc_user: &RwLock<Entity<User>>, user_id: UserId, new: String, keep: Option<TokenId>) {
+    let hash = password::hash(new).await;
+
+    let mut user = arc_user.write().await;
+    user.secret.password_hash = hash;
+    user.secret.password_salt.clear();
+    user.secret.reset_code = None;
+
+    let sessions = user.revoke_tokens(|record| Some(record.id) != keep);
+    save_user(user_id, &user);
+    drop(user);
+
+    close_sessions(user_id, sessions).await;
+}
+
 #[allow(unused_variables)]
 impl Session {
     pub(super) async fn handle_send_challenge(
         &mut self,
         num: usize,
-        a: Email,
-        b: ChallengeTarget,
+        email: Email,
+        target: ChallengeTarget,
     ) -> Result<Reply, ErrMsg> {
-        Err("unimplemented")
+        if !valid_email(&email) {
+            return Err("Invalid email address");
+        }
+
+        // a challenge counts as a failure until completed, so
+        // that the same address can't be sent many mails
+        let keys = [Key::peer(self.peer_addr.ip()), Key::email(&email)];
+        throttle::attempt(&keys).await?;
+        throttle::failure(&keys[1..]).await;
+
+        let registered = DATABASE.user_id_by_email(&email).await.is_some();
+        match target {
+            ChallengeTarget::AccountCreation | ChallengeTarget::EmailUpdate if registered => {
+                return Err("Email address already in use");
+            },
+            ChallengeTarget::EmailUpdate if self.user_id.is_none() => {
+                return Err("Not logged in yet");
+            },
+            // don't tell whether this address is registered
+            ChallengeTarget::Login | ChallengeTarget::PasswordReset if !registered => {
+                self.challenge = None;
+                return Ok(Reply::new(num, ReplyData::GenericSuccess));
+            },
+            _ => (),
+        }
+
+        self.challenge = Some(PendingChallenge::send(email, target).await?);
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
     }
 
     pub(super) async fn handle_complete_challenge(
         &mut self,
         num: usize,
-        a: Code,
+        code: Code,
     ) -> Result<Reply, ErrMsg> {
-        Err("unimplemented")
+        let email = &self.challenge.as_ref().ok_or("No pending challenge")?.email;
+        let keys = [Key::peer(self.peer_addr.ip()), Key::email(email)];
+        throttle::attempt(&keys).await?;
+
+        let mut challenge = self.challenge.take().unwrap();
+        match challenge.verify(&code) {
+            Ok(true) => throttle::success(keys[1].clone()).await,
+            Ok(false) => {
+                throttle::failure(&keys).await;
+                self.challenge = Some(challenge);
+                return Err("Wrong code");
+            },
+            Err(msg) => {
+                throttle::failure(&keys).await;
+                return Err(msg);
+            },
+        }
+
+        let email = challenge.email;
+        let reply_data = match challenge.target {
+            ChallengeTarget::AccountCreation => {
+                self.verified_email = Some(email);
+                ReplyData::GenericSuccess
+            },
+            ChallengeTarget::Login => {
+                let user_id = DATABASE.user_id_by_email(&email).await.ok_or("No such user")?;
+                let arc_user = DATABASE.users.find(user_id).await.ok_or("No such user")?;
+                let mut user = arc_user.write().await;
+                if user.secret.disabled {
+                    return Err("Account disabled");
+                }
+
+                if user.secret.two_factor.is_some() {
+                    return Err("Two-factor authentication is enabled, log in with your password");
+                }
+
+                let token = user.new_token("Email login".into());
+                save_tokens(user_id, &user);
+                ReplyData::Credentials(user_id, token)
+            },
+            ChallengeTarget::PasswordReset => {
+                let user_id = DATABASE.user_id_by_email(&email).await.ok_or("No such user")?;
+                self.password_reset = Some(user_id);
+                ReplyData::GenericSuccess
+            },
+            ChallengeTarget::EmailUpdate => {
+                let (arc_user, user_id) = self.get_user().await?;
+                let mut emails = DATABASE.emails.write().await;
+                if emails.contains_key(&email) {
+                    return Err("Email address already in use");
+                }
+
+                let mut user = arc_user.write().await;
+                let old_email = replace(&mut user.public.email, email.clone());
+                emails.remove(&old_email);
+                journal::append(JournalEntry::Email(old_email, None));
+                emails.insert(email.clone(), user_id);
+                journal::append(JournalEntry::Email(email, Some(user_id)));
+                drop(emails);
+
+                user.metadata.revision += 1;
+                let update = Update::user(user_id, user.metadata.revision, &user.public);
+                user.commit(user_id, &update);
+                drop(user);
+
+                DATABASE.notify_users(update).await;
+                ReplyData::GenericSuccess
+            },
+        };
+
+        Ok(Reply::new(num, reply_data))
     }
 
     pub(super) async fn handle_create_account(
@@ -43,14 +182,15 @@ impl Session {
         name: Username,
         password: String,
     ) -> Result<Reply, ErrMsg> {
-        let password_salt: [u8; 32] = rand::random();
-        let password_hash = crypto_hash(Some(password_salt), password);
+        password::check_policy(&password)?;
+        let peer = [Key::peer(self.peer_addr.ip())];
+        throttle::attempt(&peer).await?;
 
-        if true {
-            let reader = DATABASE.usernames.read().await;
-            if reader.contains_key(&name) {
-                return Err("Username already taken");
-            }
+        let password_hash = password::hash(password).await;
+
+        if DATABASE.usernames.read().await.contains_key(&name) {
+            throttle::failure(&peer).await;
+            return Err("Username already taken");
         }
 
         let metadata = EntityData {
@@ -58,6 +198,7 @@ impl Session {
             author: 0, // set after insertion
             guests: Vec::new(),
             revision: 0,
+            banned: Vec::new(),
         };
 
         let user_id = DATABASE.users.new_entity(metadata).await;
@@ -65,45 +206,114 @@ impl Session {
         if true {
             let mut writer = DATABASE.usernames.write().await;
             let _ = writer.insert(name.clone(), user_id);
+            journal::append(JournalEntry::Username(name.clone(), Some(user_id)));
         }
 
+        let email = match self.verified_email.take() {
+            Some(email) => {
+                let mut writer = DATABASE.emails.write().await;
+                match writer.contains_key(&email) {
+                    true => Email::default(),
+                    false => {
+                        writer.insert(email.clone(), user_id);
+                        journal::append(JournalEntry::Email(email.clone(), Some(user_id)));
+                        email
+                    },
+                }
+            },
+            None => Email::default(),
+        };
+
         let arc_user = DATABASE.users.find(user_id).await.unwrap();
         let mut user = arc_user.write().await;
 
         user.secret.server_admin = user_id == 0;
         user.secret.password_hash = password_hash;
-        user.secret.password_salt = to_hex(password_salt);
-        user.secret.max_file_size = 50 * 1024 * 1024; // 50 MiB
+        user.secret.max_file_size = config().max_file_size;
+        user.secret.storage_quota = config().storage_quota;
         user.metadata.author = user_id;
         user.public = UserData {
             name,
-            email: Email::default(),
+            email,
             status: "Exploring".to_string(),
         };
 
+        save_user(user_id, &user);
         Ok(Reply::new(num, ReplyData::ValidUsername(user_id)))
     }
 
     pub(super) async fn handle_get_token(
         &mut self,
         num: usize,
-        user_id: UserId,
+        username: Username,
         password: String,
+        label: String,
+        code: Option<Code>,
     ) -> Result<Reply, ErrMsg> {
+        let peer = [Key::peer(self.peer_addr.ip())];
+
+        // unknown usernames look like wrong passwords, timing included
+        let Some(user_id) = DATABASE.user_id(&username).await else {
+            throttle::attempt(&peer).await?;
+            password::verify_dummy(&password).await;
+            throttle::failure(&peer).await;
+            return Err("Wrong password");
+        };
+
+        let keys = [Key::peer(self.peer_addr.ip()), Key::Account(user_id)];
+        throttle::attempt(&keys).await?;
+
         let arc_user = DATABASE.users.find(user_id).await.ok_or("No such user")?;
-        let mut user = arc_user.write().await;
 
-        let password_salt = from_hex(&user.secret.password_salt).ok();
-        let password_hash = crypto_hash(password_salt, password);
+        let (stored, legacy_salt) = {
+            let user = arc_user.read().await;
+            (user.secret.password_hash.clone(), user.secret.password_salt.clone())
+        };
 
-        if user.secret.password_hash != password_hash {
+        let check = password::verify(&password, &stored, &legacy_salt).await;
+        if check == Check::Wrong {
+            throttle::failure(&keys).await;
             return Err("Wrong password");
         }
 
-        let token = to_hex(rand::random());
-        user.tokens.push(token.clone());
+        let rehash = match check {
+            Check::Outdated => Some(password::hash(password).await),
+            _ => None,
+        };
+
+        let mut user = arc_user.write().await;
 
-        Ok(Reply::new(num, ReplyData::AuthenticationToken(token)))
+        // only told to those who know the password
+        if user.secret.disabled {
+            return Err("Account disabled");
+        }
+
+        if let Some(two_factor) = &mut user.secret.two_factor {
+            let code = code.ok_or("Two-factor code required")?;
+            if !two_factor.check(&code) {
+                drop(user);
+                throttle::failure(&keys).await;
+                return Err("Wrong two-factor code");
+            }
+        }
+
+        throttle::success(Key::Account(user_id)).await;
+
+        // unless the password was changed in the meantime
+        let rehash = rehash.filter(|_| user.secret.password_hash == stored);
+        let rehashed = rehash.is_some();
+        if let Some(hash) = rehash {
+            user.secret.password_hash = hash;
+            user.secret.password_salt.clear();
+        }
+
+        let label = label.chars().take(MAX_TOKEN_LABEL).collect();
+        let token = user.new_token(label);
+        match rehashed {
+            true => save_user(user_id, &user),
+            false => save_tokens(user_id, &user),
+        }
+        Ok(Reply::new(num, ReplyData::Credentials(user_id, token)))
     }
 
     pub(super) async fn handle_open_session(
@@ -119,14 +329,20 @@ impl Session {
         let arc_user = DATABASE.users.find(user_id).await.ok_or("No such user")?;
         let mut user = arc_user.write().await;
 
-        if user.tokens.contains(&token) {
+        if user.secret.disabled {
+            return Err("Account disabled");
+        }
+
+        if let Some(token_id) = user.use_token(&token) {
             let (tx_update, rx_update) = async_channel::unbounded();
 
             let id = self.session_id;
-            user.set_tx_update(id, tx_update);
+            user.set_tx_update(id, token_id, tx_update);
+            save_tokens(user_id, &user);
 
             self.rx_update = Some(rx_update);
             self.user_id = Some(user_id);
+            self.token_id = Some(token_id);
 
             Ok(Reply::new(num, ReplyData::GenericSuccess))
         } else {
@@ -134,6 +350,233 @@ impl Session {
         }
     }
 
+    pub(super) async fn handle_list_tokens(&mut self, num: usize) -> Result<Reply, ErrMsg> {
+        let (arc_user, _user_id) = self.get_user().await?;
+        let tokens = arc_user.read().await.token_infos(self.token_id);
+        Ok(Reply::new(num, ReplyData::Tokens(tokens)))
+    }
+
+    pub(super) async fn handle_change_password(
+        &mut self,
+        num: usize,
+        old: String,
+        new: String,
+    ) -> Result<Reply, ErrMsg> {
+        let (arc_user, user_id) = self.get_user().await?;
+        password::check_policy(&new)?;
+        let keys = [Key::peer(self.peer_addr.ip()), Key::Account(user_id)];
+        throttle::attempt(&keys).await?;
+
+        let (stored, legacy_salt) = {
+            let user = arc_user.read().await;
+            (user.secret.password_hash.clone(), user.secret.password_salt.clone())
+        };
+
+        if password::verify(&old, &stored, &legacy_salt).await == Check::Wrong {
+            throttle::failure(&keys).await;
+            return Err("Wrong password");
+        }
+
+        set_password(&arc_user, user_id, new, self.token_id).await;
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    pub(super) async fn handle_reset_password(&mut self, num: usize, new: String) -> Result<Reply, ErrMsg> {
+        password::check_policy(&new)?;
+        let user_id = self.password_reset.take().ok_or("No completed challenge")?;
+        let arc_user = DATABASE.users.find(user_id).await.ok_or("No such user")?;
+        if arc_user.read().await.secret.disabled {
+            return Err("Account disabled");
+        }
+
+        set_password(&arc_user, user_id, new, None).await;
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    pub(super) async fn handle_redeem_reset_code(
+        &mut self,
+        num: usize,
+        username: Username,
+        code: Code,
+        new: String,
+    ) -> Result<Reply, ErrMsg> {
+        password::check_policy(&new)?;
+        let peer = [Key::peer(self.peer_addr.ip())];
+
+        let Some(user_id) = DATABASE.user_id(&username).await else {
+            throttle::attempt(&peer).await?;
+            throttle::failure(&peer).await;
+            return Err("Invalid reset code");
+        };
+
+        let keys = [Key::peer(self.peer_addr.ip()), Key::Account(user_id)];
+        throttle::attempt(&keys).await?;
+
+        let arc_user = DATABASE.users.find(user_id).await.ok_or("No such user")?;
+        let (valid, disabled) = {
+            let user = arc_user.read().await;
+            let valid = user.secret.reset_code.as_ref().is_some_and(|reset| reset.matches(&code));
+            (valid, user.secret.disabled)
+        };
+
+        if !valid {
+            throttle::failure(&keys).await;
+            return Err("Invalid reset code");
+        }
+
+        if disabled {
+            return Err("Account disabled");
+        }
+
+        set_password(&arc_user, user_id, new, None).await;
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    pub(super) async fn handle_issue_reset_code(&mut self, num: usize, target: UserId) -> Result<Reply, ErrMsg> {
+        let (arc_user, _user_id) = self.get_user().await?;
+        if !arc_user.read().await.secret.server_admin {
+            return Err("Not an admin!");
+        }
+
+        let arc_target = DATABASE.users.find(target).await.ok_or("No such user")?;
+        let (code, reset_code) = ResetCode::new(RESET_CODE_LIFETIME);
+
+        let mut user = arc_target.write().await;
+        user.secret.reset_code = Some(reset_code);
+        save_user(target, &user);
+
+        Ok(Reply::new(num, ReplyData::ResetCode(code)))
+    }
+
+    pub(super) async fn handle_setup_two_factor(&mut self, num: usize) -> Result<Reply, ErrMsg> {
+        let (arc_user, _user_id) = self.get_user().await?;
+        let user = arc_user.read().await;
+        if user.secret.two_factor.is_some() {
+            return Err("Two-factor authentication already enabled");
+        }
+
+        let secret = totp::new_secret();
+        let uri = totp::provisioning_uri(&secret, &user.public.name);
+        self.two_factor_setup = Some(secret.clone());
+
+        Ok(Reply::new(num, ReplyData::TwoFactorSetup(secret, uri)))
+    }
+
+    pub(super) async fn handle_enable_two_factor(&mut self, num: usize, code: Code) -> Result<Reply, ErrMsg> {
+        let (arc_user, user_id) = self.get_user().await?;
+        let secret = self.two_factor_setup.as_ref().ok_or("No two-factor setup in progress")?;
+        let step = totp::verify(secret, &code, 0).ok_or("Wrong two-factor code")?;
+
+        let secret = self.two_factor_setup.take().unwrap();
+        let mut two_factor = TwoFactor::new(secret, step);
+        let codes = two_factor.new_recovery_codes();
+
+        let mut user = arc_user.write().await;
+        if user.secret.two_factor.is_some() {
+            return Err("Two-factor authentication already enabled");
+        }
+
+        user.secret.two_factor = Some(two_factor);
+        save_user(user_id, &user);
+
+        Ok(Reply::new(num, ReplyData::RecoveryCodes(codes)))
+    }
+
+    pub(super) async fn handle_disable_two_factor(
+        &mut self,
+        num: usize,
+        password: String,
+        code: Code,
+    ) -> Result<Reply, ErrMsg> {
+        let (arc_user, user_id) = self.get_user().await?;
+        let keys = [Key::peer(self.peer_addr.ip()), Key::Account(user_id)];
+        throttle::attempt(&keys).await?;
+
+        let (stored, legacy_salt) = {
+            let user = arc_user.read().await;
+            (user.secret.password_hash.clone(), user.secret.password_salt.clone())
+        };
+
+        if password::verify(&password, &stored, &legacy_salt).await == Check::Wrong {
+            throttle::failure(&keys).await;
+            return Err("Wrong password");
+        }
+
+        let mut user = arc_user.write().await;
+        let two_factor = user.secret.two_factor.as_mut().ok_or("Two-factor authentication isn't enabled")?;
+        if !two_factor.check(&code) {
+            drop(user);
+            throttle::failure(&keys).await;
+            return Err("Wrong two-factor code");
+        }
+
+        user.secret.two_factor = None;
+        save_user(user_id, &user);
+
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    pub(super) async fn handle_new_recovery_codes(&mut self, num: usize, code: Code) -> Result<Reply, ErrMsg> {
+        let (arc_user, user_id) = self.get_user().await?;
+        let keys = [Key::peer(self.peer_addr.ip()), Key::Account(user_id)];
+        throttle::attempt(&keys).await?;
+
+        let mut user = arc_user.write().await;
+        let two_factor = user.secret.two_factor.as_mut().ok_or("Two-factor authentication isn't enabled")?;
+        if !two_factor.check(&code) {
+            drop(user);
+            throttle::failure(&keys).await;
+            return Err("Wrong two-factor code");
+        }
+
+        let codes = two_factor.new_recovery_codes();
+        save_user(user_id, &user);
+
+        Ok(Reply::new(num, ReplyData::RecoveryCodes(codes)))
+    }
+
+    pub(super) async fn handle_reset_two_factor(&mut self, num: usize, target: UserId) -> Result<Reply, ErrMsg> {
+        let (arc_user, _user_id) = self.get_user().await?;
+        if !arc_user.read().await.secret.server_admin {
+            return Err("Not an admin!");
+        }
+
+        let arc_target = DATABASE.users.find(target).await.ok_or("No such user")?;
+        let mut user = arc_target.write().await;
+        user.secret.two_factor = None;
+        save_user(target, &user);
+
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    pub(super) async fn handle_revoke_token(
+        &mut self,
+        num: usize,
+        token_id: Option<TokenId>,
+    ) -> Result<Reply, ErrMsg> {
+        let token_id = token_id.or(self.token_id).ok_or("No such token")?;
+        self.revoke_tokens(|record| record.id == token_id).await
+            .map(|()| Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    pub(super) async fn handle_revoke_other_tokens(&mut self, num: usize) -> Result<Reply, ErrMsg> {
+        let current = self.token_id;
+        self.revoke_tokens(|record| Some(record.id) != current).await
+            .map(|()| Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    async fn revoke_tokens<F: Fn(&TokenRecord) -> bool>(&mut self, revoke: F) -> Result<(), ErrMsg> {
+        let (arc_user, user_id) = self.get_user().await?;
+
+        let mut user = arc_user.write().await;
+        let sessions = user.revoke_tokens(revoke);
+        save_tokens(user_id, &user);
+        drop(user);
+
+        close_sessions(user_id, sessions).await;
+        Ok(())
+    }
+
     pub(super) async fn handle_load_user_data(
         &mut self,
         num: usize,
@@ -148,10 +591,22 @@ impl Session {
         let revision = user.metadata.revision;
         let image = user.metadata.image.clone();
         let public = user.public.clone();
-        let secret = user.secret.clone();
+        let mut secret = Box::new(user.secret.clone());
         drop(user);
 
         let reply_data = if is_self {
+            // credentials never leave the server
+            secret.password_hash.clear();
+            secret.password_salt.clear();
+            secret.reset_code = None;
+
+            if let Some(two_factor) = &mut secret.two_factor {
+                two_factor.secret.clear();
+                two_factor.recovery_codes.clear();
+            }
+
+            secret.storage.copies.clear();
+
             let num_ent = secret.entities.len();
             let mut entity_map = LiteMap::with_capacity(num_ent + 1);
 
@@ -176,7 +631,7 @@ impl Session {
         &mut self,
         num: usize,
         rev: Revision,
-        data: UserData,
+        mut data: UserData,
     ) -> Result<Reply, ErrMsg> {
         let (arc_user, user_id) = self.get_user().await?;
 
@@ -186,9 +641,13 @@ impl Session {
             return Err("Out of date");
         }
 
+        // changed through an EmailUpdate challenge only
+        data.email = user.public.email.clone();
+
         user.metadata.revision += 1;
         let update = Update::user(user_id, user.metadata.revision, &data);
         user.public = data;
+        user.commit(user_id, &update);
 
         drop(user);
 
@@ -211,6 +670,11 @@ impl Session {
             data.cloned().ok_or("Invalid invite")?
         };
 
+        let metadata = DATABASE.metadata(data.target).await.ok_or("No such entity")?;
+        if metadata.banned.contains(&user_id) && !discard {
+            return Err("Banned from this entity");
+        }
+
         let access = EntityAccess {
             read_only: data.read_only,
             local_name: data.orig_name,
@@ -218,26 +682,48 @@ impl Session {
             last_seen_rev: 0,
         };
 
-        if true {
+        let friend_id = match (data.target, discard) {
+            (EntityId::User(friend_id), false) => Some(friend_id),
+            _ => None,
+        };
+
+        let arc_friend = match friend_id {
+            Some(friend_id) => Some(DATABASE.users.find(friend_id).await.ok_or("friend gone")?),
+            None => None,
+        };
+
+        // the invite is opened in a single revision
+        let (update, sessions) = {
             let mut user = arc_user.write().await;
             if user.metadata.revision != revision {
                 return Err("bad revision");
             }
 
             if !discard {
-                // todo: send update to guests
                 user.secret.entities.insert(data.target, access);
             }
 
-            user.metadata.revision += 1;
+            // friends are guests of each other
+            if let Some(friend_id) = friend_id {
+                user.metadata.guests.push(friend_id);
+            }
+
             user.secret.invites.remove(invite);
+            user.metadata.revision += 1;
+            let rev = user.metadata.revision;
+            let update = Update::new(UpdateType::ByeInvite, EntityId::User(user_id), rev, invite as u64, &());
+            user.commit(user_id, &update);
+            save_user(user_id, &user);
+            (Arc::new(update), user.sessions.clone())
         };
 
+        // other devices of this user
+        for tx_update in sessions.iter_values() {
+            let _ = tx_update.send(update.clone()).await;
+        }
+
         // friendship! make it reciprocal
-        let mut maybe_update = None;
-        if let (EntityId::User(friend_id), false) = (data.target, discard) {
-            let our_id = EntityId::User(user_id);
-            let arc_friend = DATABASE.users.find(friend_id).await.ok_or("friend gone")?;
+        if let (Some(friend_id), Some(arc_friend)) = (friend_id, arc_friend) {
             let access = EntityAccess {
                 read_only: true,
                 local_name: "Friend Request".into(),
@@ -246,38 +732,42 @@ impl Session {
             };
 
             let mut friend = arc_friend.write().await;
+            friend.secret.entities.insert(EntityId::User(user_id), access);
+            friend.metadata.guests.push(user_id);
             friend.metadata.revision += 1;
-            friend.secret.entities.insert(our_id, access);
+            let update = Update::friend(friend_id, user_id, friend.metadata.revision);
+            friend.commit(user_id, &update);
+            save_user(friend_id, &friend);
             let sessions = friend.sessions.clone();
             drop(friend);
 
-            DATABASE.push_guest(our_id, friend_id).await;
-
-            let update = Update::friend(friend_id, user_id);
-            maybe_update = Some((Arc::new(update), sessions));
-        } else if !discard {
-            let update = Update::new(UpdateType::NewGuest, data.target, 0, 0, &user_id);
-            DATABASE.notify_users(update).await;
-        }
-
-        DATABASE.push_guest(data.target, user_id).await;
-
-        if let Some((update, sessions)) = maybe_update {
+            let update = Arc::new(update);
             for tx_update in sessions.iter_values() {
                 println!("notifying one user session");
                 let _ = tx_update.send(update.clone()).await;
             }
+        } else if !discard {
+            for update in DATABASE.push_guest(data.target, user_id).await {
+                DATABASE.notify_users(update).await;
+            }
         }
 
         Ok(Reply::new(num, ReplyData::GenericSuccess))
     }
 
+    /// Logging in doesn't need it, so that usernames can't be
+    /// probed without an account
     pub(super) async fn handle_who_is(&mut self, num: usize, username: Username) -> Result<Reply, ErrMsg> {
-        // self.user_id.ok_or("Not logged in yet")?;
-        let reader = DATABASE.usernames.read().await;
-        match reader.get(&username) {
-            Some(user_id) => Ok(Reply::new(num, ReplyData::ValidUsername(*user_id))),
-            None => Err("Invalid username"),
+        self.get_user().await?;
+        let peer = [Key::peer(self.peer_addr.ip())];
+        throttle::attempt(&peer).await?;
+
+        match DATABASE.user_id(&username).await {
+            Some(user_id) => Ok(Reply::new(num, ReplyData::ValidUsername(user_id))),
+            None => {
+                throttle::failure(&peer).await;
+                Err("Invalid username")
+            },
         }
     }
 
@@ -294,6 +784,7 @@ impl Session {
             author: user_id,
             guests: Vec::new(),
             revision: 0,
+            banned: Vec::new(),
         };
 
         let db = &DATABASE;
@@ -314,6 +805,7 @@ impl Session {
 
         let mut user = arc_user.write().await;
         user.secret.entities.insert(entity_id, access);
+        save_access(user_id, &user, entity_id);
 
         Ok(Reply::new(num, ReplyData::EntityCreated(entity_id)))
     }
@@ -323,10 +815,20 @@ impl Session {
         let user = arc_user.read().await;
 
         if user.secret.server_admin {
-            trigger_backup().await;
+            shutdown::request();
             Ok(Reply::new(num, ReplyData::GenericSuccess))
         } else {
             Err("Not an admin!")
         }
     }
+
+    pub(super) async fn handle_executor_metrics(&mut self, num: usize) -> Result<Reply, ErrMsg> {
+        let (arc_user, _user_id) = self.get_user().await?;
+        let user = arc_user.read().await;
+
+        match user.secret.server_admin {
+            true => Ok(Reply::new(num, ReplyData::ExecutorMetrics(executor::metrics()))),
+            false => Err("Not an admin!"),
+        }
+    }
 }
\ No newline at end of file
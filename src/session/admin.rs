@@ -0,0 +1,319 @@
+use async_fs::{read_dir, metadata};
+use serde::Serialize;
+
+use crate::{
+    database::{
+        EntityId,
+        objects::{UserId, Username, Email, SecretUserData},
+        entities::Revision,
+        journal::{self, JournalEntry, save_user},
+    }
+};
+
+use crate::{DATABASE, StreamExt, backup_now, shutdown};
+use crate::config::{config, SNAPSHOT, JOURNAL};
+use super::account::close_sessions;
+use super::replies::{Reply, ReplyData};
+use super::{Session, ErrMsg};
+
+use std::mem::{drop, take};
+
+/// Shown instead of the name of deleted accounts
+const DELETED_NAME: &str = "Deleted user";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserSummary {
+    pub id: UserId,
+    pub name: Username,
+    pub email: Email,
+    pub server_admin: bool,
+    pub disabled: bool,
+    pub two_factor: bool,
+    pub max_file_size: usize,
+    pub storage_used: usize,
+    pub storage_quota: usize,
+    /// Device label of each open session
+    pub sessions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EntitySummary {
+    pub id: EntityId,
+    /// None once abandoned by every user
+    pub author: Option<UserId>,
+    pub guests: Vec<UserId>,
+    pub revision: Revision,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ServerStats {
+    pub users: u32,
+    pub disabled_users: u32,
+    /// Connected clients, logged in or not
+    pub connections: usize,
+    /// Sessions opened with a token
+    pub sessions: usize,
+    pub conversations: u32,
+    pub documents: u32,
+    pub spreadsheets: u32,
+    pub buckets: u32,
+    pub abandoned_entities: u32,
+    pub stored_files: usize,
+    pub storage_bytes: u64,
+    /// Files which no bucket refers to anymore
+    pub orphaned_files: usize,
+    pub orphaned_bytes: u64,
+    pub database_bytes: u64,
+    pub journal_bytes: u64,
+}
+
+async fn file_size(path: &str) -> u64 {
+    metadata(path).await.map(|m| m.len()).unwrap_or(0)
+}
+
+impl Session {
+    async fn require_admin(&self) -> Result<UserId, ErrMsg> {
+        let (arc_user, user_id) = self.get_user().await?;
+        let is_admin = arc_user.read().await.secret.server_admin;
+
+        match is_admin {
+            true => Ok(user_id),
+            false => Err("Not an admin!"),
+        }
+    }
+
+    pub(super) async fn handle_list_users(&mut self, num: usize) -> Result<Reply, ErrMsg> {
+        self.require_admin().await?;
+
+        let mut users = Vec::new();
+        for id in 0..DATABASE.users.count().await {
+            let Some(arc_user) = DATABASE.users.find(id).await else {
+                continue;
+            };
+
+            let user = arc_user.read().await;
+            let sessions = user.session_tokens.iter_values().map(|token_id| {
+                let record = user.tokens.iter().find(|record| record.id == *token_id);
+                record.map(|record| record.label.clone()).unwrap_or_default()
+            });
+
+            users.push(UserSummary {
+                id,
+                name: user.public.name.clone(),
+                email: user.public.email.clone(),
+                server_admin: user.secret.server_admin,
+                disabled: user.secret.disabled,
+                two_factor: user.secret.two_factor.is_some(),
+                max_file_size: user.secret.max_file_size,
+                storage_used: user.secret.storage.used,
+                storage_quota: user.secret.storage_quota,
+                sessions: sessions.collect(),
+            });
+        }
+
+        Ok(Reply::new(num, ReplyData::Users(users)))
+    }
+
+    pub(super) async fn handle_list_entities(&mut self, num: usize) -> Result<Reply, ErrMsg> {
+        self.require_admin().await?;
+
+        let mut ids = Vec::new();
+        ids.extend((0..DATABASE.conversations.count().await).map(EntityId::Conversation));
+        ids.extend((0..DATABASE.documents.count().await).map(EntityId::Document));
+        ids.extend((0..DATABASE.sheets.count().await).map(EntityId::Spreadsheet));
+        ids.extend((0..DATABASE.buckets.count().await).map(EntityId::Bucket));
+
+        let mut entities = Vec::with_capacity(ids.len());
+        for id in ids {
+            let metadata = DATABASE.metadata(id).await.ok_or("Internal DB inconsistency")?;
+            entities.push(EntitySummary {
+                id,
+                author: Some(metadata.author).filter(|author| *author != UserId::MAX),
+                guests: metadata.guests,
+                revision: metadata.revision,
+            });
+        }
+
+        Ok(Reply::new(num, ReplyData::Entities(entities)))
+    }
+
+    pub(super) async fn handle_set_max_file_size(
+        &mut self,
+        num: usize,
+        target: UserId,
+        max_file_size: usize,
+    ) -> Result<Reply, ErrMsg> {
+        self.require_admin().await?;
+
+        let arc_target = DATABASE.users.find(target).await.ok_or("No such user")?;
+        let mut user = arc_target.write().await;
+        user.secret.max_file_size = max_file_size;
+        save_user(target, &user);
+
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    pub(super) async fn handle_set_storage_quota(
+        &mut self,
+        num: usize,
+        target: UserId,
+        storage_quota: usize,
+    ) -> Result<Reply, ErrMsg> {
+        self.require_admin().await?;
+
+        let arc_target = DATABASE.users.find(target).await.ok_or("No such user")?;
+        let mut user = arc_target.write().await;
+        user.secret.storage_quota = storage_quota;
+        save_user(target, &user);
+
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    pub(super) async fn handle_set_account_disabled(
+        &mut self,
+        num: usize,
+        target: UserId,
+        disabled: bool,
+    ) -> Result<Reply, ErrMsg> {
+        if self.require_admin().await? == target {
+            return Err("Admins can't disable their own account");
+        }
+
+        let arc_target = DATABASE.users.find(target).await.ok_or("No such user")?;
+        let mut user = arc_target.write().await;
+        user.secret.disabled = disabled;
+
+        let sessions = match disabled {
+            true => user.revoke_tokens(|_| true),
+            false => Vec::new(),
+        };
+
+        save_user(target, &user);
+        drop(user);
+
+        close_sessions(target, sessions).await;
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    /// User ids stay allocated: the account is emptied and disabled,
+    /// and its username and email address are freed.
+    pub(super) async fn handle_delete_account(&mut self, num: usize, target: UserId) -> Result<Reply, ErrMsg> {
+        if self.require_admin().await? == target {
+            return Err("Admins can't delete their own account");
+        }
+
+        let arc_target = DATABASE.users.find(target).await.ok_or("No such user")?;
+        let mut user = arc_target.write().await;
+        let sessions = user.revoke_tokens(|_| true);
+        let entities: Vec<EntityId> = user.secret.entities.iter_keys().copied().collect();
+
+        let name = take(&mut user.public.name);
+        let email = take(&mut user.public.email);
+        user.public.name = DELETED_NAME.into();
+        user.public.status.clear();
+
+        user.secret = SecretUserData {
+            disabled: true,
+            ..Default::default()
+        };
+
+        save_user(target, &user);
+        drop(user);
+
+        close_sessions(target, sessions).await;
+
+        for entity_id in entities {
+            for update in DATABASE.drop_access(entity_id, target).await {
+                DATABASE.notify_users(update).await;
+            }
+        }
+
+        let mut usernames = DATABASE.usernames.write().await;
+        if usernames.get(&name) == Some(&target) {
+            usernames.remove(&name);
+            journal::append(JournalEntry::Username(name, None));
+        }
+
+        drop(usernames);
+
+        let mut emails = DATABASE.emails.write().await;
+        if emails.get(&email) == Some(&target) {
+            emails.remove(&email);
+            journal::append(JournalEntry::Email(email, None));
+        }
+
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    pub(super) async fn handle_force_backup(&mut self, num: usize) -> Result<Reply, ErrMsg> {
+        self.require_admin().await?;
+        backup_now().await;
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    pub(super) async fn handle_server_stats(&mut self, num: usize) -> Result<Reply, ErrMsg> {
+        self.require_admin().await?;
+
+        let mut stats = ServerStats {
+            connections: shutdown::open_sessions(),
+            conversations: DATABASE.conversations.count().await,
+            documents: DATABASE.documents.count().await,
+            spreadsheets: DATABASE.sheets.count().await,
+            buckets: DATABASE.buckets.count().await,
+            database_bytes: file_size(&config().database_path(SNAPSHOT)).await,
+            journal_bytes: file_size(&config().database_path(JOURNAL)).await,
+            ..Default::default()
+        };
+
+        for id in 0..DATABASE.users.count().await {
+            let Some(arc_user) = DATABASE.users.find(id).await else {
+                continue;
+            };
+
+            let user = arc_user.read().await;
+            stats.users += 1;
+            stats.disabled_users += user.secret.disabled as u32;
+            stats.sessions += user.sessions.len();
+        }
+
+        let kinds = [
+            (stats.conversations, EntityId::Conversation as fn(u32) -> EntityId),
+            (stats.documents, EntityId::Document),
+            (stats.spreadsheets, EntityId::Spreadsheet),
+            (stats.buckets, EntityId::Bucket),
+        ];
+
+        for (count, entity_id) in kinds {
+            for id in 0..count {
+                let metadata = DATABASE.metadata(entity_id(id)).await;
+                let abandoned = metadata.is_some_and(|m| m.author == UserId::MAX);
+                stats.abandoned_entities += abandoned as u32;
+            }
+        }
+
+        let file_rc = DATABASE.file_rc.read().await.clone();
+        let mut entries = read_dir(&config().files_dir).await.map_err(|_| "Couldn't read the files directory")?;
+
+        while let Some(Ok(entry)) = entries.next().await {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(hash) = name.strip_suffix(".dat") else {
+                continue;
+            };
+
+            let size = entry.metadata().await.map(|m| m.len()).unwrap_or(0);
+            match file_rc.get(hash) {
+                Some(counter) if *counter > 0 => {
+                    stats.stored_files += 1;
+                    stats.storage_bytes += size;
+                },
+                // including unfinished uploads
+                _ => {
+                    stats.orphaned_files += 1;
+                    stats.orphaned_bytes += size;
+                },
+            }
+        }
+
+        Ok(Reply::new(num, ReplyData::ServerStats(stats)))
+    }
+}
@@ -0,0 +1,111 @@
+use rand::Rng;
+
+use crate::database::objects::Email;
+use crate::mail::{Mail, send_mail};
+
+use super::requests::{ChallengeTarget, Code};
+use super::ErrMsg;
+
+use std::time::{Instant, Duration};
+
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+const CHALLENGE_ATTEMPTS: u8 = 3;
+
+/// One-time code sent by email, waiting for `CompleteChallenge`
+#[derive(Debug)]
+pub struct PendingChallenge {
+    pub email: Email,
+    pub target: ChallengeTarget,
+    code: Code,
+    expires: Instant,
+    attempts_left: u8,
+}
+
+impl PendingChallenge {
+    pub async fn send(email: Email, target: ChallengeTarget) -> Result<Self, ErrMsg> {
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+
+        let action = match target {
+            ChallengeTarget::AccountCreation => "create your account",
+            ChallengeTarget::Login => "log in",
+            ChallengeTarget::EmailUpdate => "confirm your new email address",
+            ChallengeTarget::PasswordReset => "reset your password",
+        };
+
+        let minutes = CHALLENGE_LIFETIME.as_secs() / 60;
+        let mail = Mail {
+            to: email.clone(),
+            subject: format!("Kolab code: {code}"),
+            body: format!("Use this code to {action}: {code}\r\nIt expires in {minutes} minutes."),
+        };
+
+        if let Err(msg) = send_mail(&mail).await {
+            println!("Failed to send challenge: {}", msg);
+            return Err("Couldn't send email");
+        }
+
+        Ok(Self {
+            email,
+            target,
+            code,
+            expires: Instant::now() + CHALLENGE_LIFETIME,
+            attempts_left: CHALLENGE_ATTEMPTS,
+        })
+    }
+
+    /// Returns Ok(true) if the code is right, Ok(false) if
+    /// it isn't but the challenge can be attempted again.
+    pub fn verify(&mut self, code: &str) -> Result<bool, ErrMsg> {
+        if Instant::now() > self.expires {
+            return Err("Challenge expired");
+        }
+
+        if same_code(&self.code, code.trim()) {
+            return Ok(true);
+        }
+
+        self.attempts_left -= 1;
+        match self.attempts_left {
+            0 => Err("Wrong code, challenge cancelled"),
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Compares codes in constant time, to not leak how many digits match
+fn same_code(expected: &str, code: &str) -> bool {
+    let diff = expected.bytes().zip(code.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b));
+    expected.len() == code.len() && diff == 0
+}
+
+/// Rejects addresses which could inject mail headers
+pub fn valid_email(email: &str) -> bool {
+    let Some((user, domain)) = email.split_once('@') else {
+        return false;
+    };
+
+    let forbidden = |c: char| c.is_whitespace() || c.is_control() || "<>,;".contains(c);
+    !user.is_empty() && domain.contains('.') && !email.contains(forbidden)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes() {
+        assert!(same_code("012345", "012345"));
+        assert!(!same_code("012345", "012346"));
+        assert!(!same_code("012345", "01234"));
+        assert!(!same_code("012345", ""));
+    }
+
+    #[test]
+    fn emails() {
+        assert!(valid_email("someone@example.com"));
+        assert!(!valid_email("someone@localhost"));
+        assert!(!valid_email("@example.com"));
+        assert!(!valid_email("a@example.com\r\nBcc: b@example.com"));
+        assert!(!valid_email("a@example.com>, <b@example.com"));
+    }
+}
@@ -4,35 +4,74 @@ use crate::{
         update::{Update, UpdateType},
         objects::{Username, UserId},
         entities::{EntityTag, Revision},
+        journal::{save_user, save_access},
     }
 };
 
 use crate::DATABASE;
+use super::requests::MessageCursor;
 use super::replies::{Reply, ReplyData};
 use super::{Session, ErrMsg};
 
 use std::sync::Arc;
+use std::mem::drop;
+
+/// Maximum number of history records per LoadHistory reply
+const MAX_HISTORY_PAGE: usize = 256;
 
 #[allow(unused_variables)]
 impl Session {
     pub(super) async fn handle_load_history(
         &mut self,
         num: usize,
-        a: EntityId,
+        entity_id: EntityId,
+        since: Revision,
+        limit: usize,
     ) -> Result<Reply, ErrMsg> {
-        Err("unimplemented")
+        let (arc_user, _user_id) = self.get_user().await?;
+        arc_user.check_access_to(entity_id, false).await?;
+
+        let limit = limit.min(MAX_HISTORY_PAGE);
+        let history = DATABASE.history(entity_id, since, limit).await.ok_or("No such entity")?;
+        Ok(Reply::new(num, ReplyData::History(history)))
     }
 
-    pub(super) async fn handle_set_last_seen(
+    pub(super) async fn handle_sync_since(
         &mut self,
         num: usize,
         entity_id: EntityId,
         revision: Revision,
     ) -> Result<Reply, ErrMsg> {
         let (arc_user, _user_id) = self.get_user().await?;
+        arc_user.check_access_to(entity_id, false).await?;
+
+        if let Some((current, updates)) = DATABASE.updates_since(entity_id, revision).await {
+            return Ok(Reply::new(num, ReplyData::Updates(current, updates)));
+        }
+
+        // too far behind: send a full snapshot instead
+        match entity_id {
+            EntityId::Conversation(id) => {
+                self.handle_load_messages_before(num, id, MessageCursor::Latest).await
+            },
+            EntityId::Document(id) => self.handle_load_document(num, id).await,
+            EntityId::Spreadsheet(id) => self.handle_load_spreadsheet(num, id).await,
+            EntityId::Bucket(id) => self.handle_load_bucket(num, id).await,
+            EntityId::User(id) => self.handle_load_user_data(num, Some(id)).await,
+        }
+    }
+
+    pub(super) async fn handle_set_last_seen(
+        &mut self,
+        num: usize,
+        entity_id: EntityId,
+        revision: Revision,
+    ) -> Result<Reply, ErrMsg> {
+        let (arc_user, user_id) = self.get_user().await?;
         let mut user = arc_user.write().await;
         let access = user.secret.entities.get_mut(&entity_id).ok_or("No such entity")?;
         access.last_seen_rev = revision;
+        save_access(user_id, &user, entity_id);
         Ok(Reply::new(num, ReplyData::GenericSuccess))
     }
 
@@ -42,11 +81,12 @@ impl Session {
         entity_id: EntityId,
         tags: Vec<EntityTag>,
     ) -> Result<Reply, ErrMsg> {
-        let (arc_user, _user_id) = self.get_user().await?;
+        let (arc_user, user_id) = self.get_user().await?;
 
         let mut user = arc_user.write().await;
         let access = user.secret.entities.get_mut(&entity_id).ok_or("No such entity")?;
         access.tags = tags;
+        save_access(user_id, &user, entity_id);
         Ok(Reply::new(num, ReplyData::GenericSuccess))
     }
 
@@ -56,11 +96,12 @@ impl Session {
         entity_id: EntityId,
         name: String,
     ) -> Result<Reply, ErrMsg> {
-        let (arc_user, _user_id) = self.get_user().await?;
+        let (arc_user, user_id) = self.get_user().await?;
 
         let mut user = arc_user.write().await;
         let access = user.secret.entities.get_mut(&entity_id).ok_or("No such entity")?;
         access.local_name = name;
+        save_access(user_id, &user, entity_id);
         Ok(Reply::new(num, ReplyData::GenericSuccess))
     }
 
@@ -100,6 +141,10 @@ impl Session {
                 return Err("Guest already has access");
             }
 
+            if metadata.banned.contains(guest_id) {
+                return Err("Guest is banned");
+            }
+
             let maybe_arc = DATABASE.users.find(*guest_id).await;
             let arc_guest = maybe_arc.ok_or("No such guest user id")?;
             let guest = arc_guest.read().await;
@@ -119,6 +164,7 @@ impl Session {
             let arc_user = DATABASE.users.find(guest_id).await.unwrap();
             let mut user = arc_user.write().await;
             user.secret.invites.push(invite_data.clone());
+            save_user(guest_id, &user);
             let sessions = user.sessions.clone();
             let data = serde_json::Value::Null;
             core::mem::drop(user);
@@ -146,19 +192,81 @@ impl Session {
     pub(super) async fn handle_transfer_ownership(
         &mut self,
         num: usize,
-        a: EntityId,
-        b: Username,
+        entity_id: EntityId,
+        username: Username,
     ) -> Result<Reply, ErrMsg> {
-        Err("unimplemented")
+        let (_arc_user, user_id) = self.get_user().await?;
+        if let EntityId::User(_) = entity_id {
+            return Err("Cannot transfer a user");
+        }
+
+        let new_author = DATABASE.user_id(&username).await.ok_or("Invalid username")?;
+        let arc_new_author = DATABASE.users.find(new_author).await.ok_or("No such user")?;
+        let updates = DATABASE.commit_metadata(entity_id, user_id, |m| m.transfer(user_id, new_author)).await?;
+
+        if true {
+            let mut author = arc_new_author.write().await;
+            if let Some(access) = author.secret.entities.get_mut(&entity_id) {
+                access.read_only = false;
+            }
+            save_access(new_author, &author, entity_id);
+        }
+
+        for update in updates {
+            DATABASE.notify_users(update).await;
+        }
+
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
     }
 
     pub(super) async fn handle_ban_guest(
         &mut self,
         num: usize,
-        a: EntityId,
-        b: Username,
+        entity_id: EntityId,
+        username: Username,
+    ) -> Result<Reply, ErrMsg> {
+        let (_arc_user, user_id) = self.get_user().await?;
+
+        let guest_id = DATABASE.user_id(&username).await.ok_or("Invalid username")?;
+        let arc_guest = DATABASE.users.find(guest_id).await.ok_or("No such user")?;
+        let guest = arc_guest.read().await;
+        let invited = guest.secret.invites.iter().any(|invite| invite.target == entity_id);
+        drop(guest);
+
+        let updates = DATABASE.commit_metadata(entity_id, user_id, |m| m.ban(user_id, guest_id, invited)).await?;
+
+        let mut guest = arc_guest.write().await;
+        guest.secret.entities.remove(&entity_id);
+        guest.secret.invites.retain(|invite| invite.target != entity_id);
+        save_user(guest_id, &guest);
+        let sessions = guest.sessions.clone();
+        drop(guest);
+
+        for update in updates {
+            // the banned user isn't a guest anymore
+            let arc_update = Arc::new(update.clone());
+            for tx_update in sessions.iter_values() {
+                let _ = tx_update.send(arc_update.clone()).await;
+            }
+
+            DATABASE.notify_users(update).await;
+        }
+
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+
+    pub(super) async fn handle_unban_guest(
+        &mut self,
+        num: usize,
+        entity_id: EntityId,
+        username: Username,
     ) -> Result<Reply, ErrMsg> {
-        Err("unimplemented")
+        let (_arc_user, user_id) = self.get_user().await?;
+
+        let banned_id = DATABASE.user_id(&username).await.ok_or("Invalid username")?;
+        DATABASE.update_metadata(entity_id, |m| m.unban(user_id, banned_id)).await?;
+
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
     }
 
     pub(super) async fn handle_drop(
@@ -167,13 +275,13 @@ impl Session {
         entity_id: EntityId,
     ) -> Result<Reply, ErrMsg> {
         let (arc_user, user_id) = self.get_user().await?;
-        DATABASE.drop_access(entity_id, user_id).await;
-
-        let update = Update::new(UpdateType::ByeGuest, entity_id, 0, 0, &user_id);
-        DATABASE.notify_users(update).await;
+        for update in DATABASE.drop_access(entity_id, user_id).await {
+            DATABASE.notify_users(update).await;
+        }
 
         let mut user = arc_user.write().await;
         user.secret.entities.remove(&entity_id);
+        save_access(user_id, &user, entity_id);
         // todo: get rid of relevant invites
         Ok(Reply::new(num, ReplyData::GenericSuccess))
     }
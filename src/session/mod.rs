@@ -1,23 +1,26 @@
-use futures_lite::future::{pending, race};
+use futures_lite::future::{pending, race, or};
 use litemap::LiteMap;
+use async_tungstenite::tungstenite::protocol::frame::{Frame, FrameHeader};
+use async_tungstenite::tungstenite::protocol::frame::coding::{OpCode, Data};
 
 use crate::{
     serde_utils::SerdeRwLock as RwLock,
     database::{
         EntityId,
-        update::Update,
-        objects::{User, UserId},
+        update::{Update, UpdateType},
+        objects::{User, UserId, Email, TokenId},
         entities::{Entity, EntityData},
     }
 };
 
 use crate::{
     DATABASE, FULL_DB_ACCESS, WebSocket, SinkExt, Message as WsMessage,
-    StreamExt, Receiver, StringifyError,
+    StreamExt, Receiver, StringifyError, shutdown, front, deflate,
 };
 
 use requests::{Request, RequestData};
 use replies::{Reply, ReplyData};
+use challenge::PendingChallenge;
 use upload::TemporaryFile;
 
 use std::net::SocketAddr;
@@ -27,22 +30,37 @@ use std::sync::Arc;
 type ErrMsg = &'static str;
 type EntitiesDataMap = LiteMap<EntityId, EntityData>;
 
+/// Messages smaller than this aren't worth deflating
+const DEFLATE_THRESHOLD: usize = 1024;
+
 mod upload;
+mod admin;
 mod account;
 mod objects;
 mod replies;
 mod entities;
 mod requests;
+mod challenge;
+mod throttle;
 
 pub type SessionId = usize;
 
 pub struct Session {
     session_id: SessionId,
-    _peer_addr: SocketAddr,
+    peer_addr: SocketAddr,
     socket: WebSocket,
+    deflate: bool,
     rx_update: Option<Receiver<Arc<Update>>>,
+    rx_reload: Receiver<()>,
     tmp_file: Option<TemporaryFile>,
     user_id: Option<UserId>,
+    token_id: Option<TokenId>,
+    challenge: Option<PendingChallenge>,
+    verified_email: Option<Email>,
+    /// User whose password can be reset, after an email challenge
+    password_reset: Option<UserId>,
+    /// Secret waiting for its first code
+    two_factor_setup: Option<String>,
 }
 
 fn get_session_id() -> usize {
@@ -52,14 +70,22 @@ fn get_session_id() -> usize {
 }
 
 impl Session {
-    pub async fn run(peer_addr: SocketAddr, socket: WebSocket) {
+    pub async fn run(peer_addr: SocketAddr, socket: WebSocket, deflate: bool) {
+        let _guard = shutdown::SessionGuard::new();
         let mut this = Self {
             session_id: get_session_id(),
-            _peer_addr: peer_addr,
+            peer_addr,
             socket,
+            deflate,
             rx_update: None,
+            rx_reload: front::reload_receiver().await,
             tmp_file: None,
             user_id: None,
+            token_id: None,
+            challenge: None,
+            verified_email: None,
+            password_reset: None,
+            two_factor_setup: None,
         };
 
         this.actually_run().await;
@@ -76,14 +102,30 @@ impl Session {
             };
 
             let msg_recv = async { Select::B(self.socket.next().await) };
-            let race_result = race(update_recv, msg_recv).await;
+            let rx_reload = self.rx_reload.clone();
+            let reload = async { Select::C(rx_reload.recv().await) };
+            let closing = async {
+                shutdown::closing().await;
+                None
+            };
+
+            let next_event = async { Some(race(update_recv, race(msg_recv, reload)).await) };
+            let race_result = or(closing, next_event).await;
+
+            let _reader = FULL_DB_ACCESS.read().await;
+
+            // requests received from now on would miss the final snapshot
+            let Some(race_result) = race_result.filter(|_| !shutdown::is_closing()) else {
+                self.notify_shutdown().await;
+                break;
+            };
 
-            let _reader = FULL_DB_ACCESS.read();
             let status = match race_result {
                 Select::A(Ok(update)) => self.handle_update(update).await,
                 Select::B(Some(Ok(WsMessage::Text(text)))) => self.handle_message(text).await,
                 Select::B(Some(Ok(WsMessage::Binary(bytes)))) => self.handle_bytes(bytes).await,
                 Select::B(Some(Ok(WsMessage::Ping(bytes)))) => self.handle_ping(bytes).await,
+                Select::C(_closed) => self.notify_reload().await,
                 other => Err(format!("Select() Error: {:?}", other)),
             };
 
@@ -101,13 +143,28 @@ impl Session {
         }
     }
 
+    /// Sends a JSON message; large ones are compressed if the
+    /// client negotiated permessage-deflate.
     async fn send(&mut self, text: String) -> Result<(), String> {
-        self.socket.send(WsMessage::Text(text)).await.fmt_err("send")
+        let message = match self.deflate && text.len() > DEFLATE_THRESHOLD {
+            true => {
+                let header = FrameHeader {
+                    rsv1: true,
+                    opcode: OpCode::Data(Data::Text),
+                    ..FrameHeader::default()
+                };
+
+                let compressed = deflate::compress(text.as_bytes()).fmt_err("deflate")?;
+                WsMessage::Frame(Frame::from_payload(header, compressed))
+            },
+            false => WsMessage::Text(text),
+        };
+
+        self.socket.send(message).await.fmt_err("send")
     }
 
     async fn handle_message(&mut self, text: String) -> Result<(), String> {
         let request: Request = serde_json::from_str(&text).fmt_err("serde")?;
-        println!("REQUEST {:?}", request);
         let num = request.num;
 
         let reply = match self.handle_request(request).await {
@@ -123,18 +180,45 @@ impl Session {
         self.send(text).await
     }
 
+    async fn notify_shutdown(&mut self) {
+        if let Some(user_id) = self.user_id {
+            let update = Update::new(UpdateType::ServerShutdown, EntityId::User(user_id), 0, 0, &());
+            let _ = self.handle_update(Arc::new(update)).await;
+        }
+
+        let _ = self.socket.close(None).await;
+    }
+
+    async fn notify_reload(&mut self) -> Result<(), String> {
+        self.rx_reload = front::reload_receiver().await;
+
+        match self.user_id {
+            Some(user_id) => {
+                let update = Update::new(UpdateType::FrontReload, EntityId::User(user_id), 0, 0, &());
+                self.handle_update(Arc::new(update)).await
+            },
+            None => Ok(()),
+        }
+    }
+
     async fn handle_update(&mut self, update: Arc<Update>) -> Result<(), String> {
         let json = serde_json::to_string(&update).fmt_err("handle_update")?;
-        let _ = self.socket.send(WsMessage::Text(json)).await;
+        let _ = self.send(json).await;
+
+        if let UpdateType::TokenRevoked = update.update_type {
+            let _ = self.socket.close(None).await;
+            return Err("Token revoked".into());
+        }
 
         Ok(())
     }
 
     async fn handle_bytes(&mut self, bytes: Vec<u8>) -> Result<(), String> {
         let (arc_user, _user_id) = self.get_user().await?;
-        let max_file_size = {
+        let (max_file_size, remaining) = {
             let user = arc_user.read().await;
-            user.secret.max_file_size
+            let remaining = user.secret.storage_quota.saturating_sub(user.secret.storage.used);
+            (user.secret.max_file_size, remaining)
         };
 
         if self.tmp_file.is_none() {
@@ -145,8 +229,14 @@ impl Session {
         let tmp_file = self.tmp_file.as_mut().unwrap();
         let new_size = tmp_file.extend_from_slice(&bytes).await;
 
-        match new_size > max_file_size {
-            true => Err("File too big".into()),
+        if new_size > max_file_size {
+            return Err("File too big".into());
+        }
+
+        // copies of files stored already aren't charged, but
+        // that is only known once the upload is complete
+        match new_size > remaining {
+            true => Err("Storage quota exceeded".into()),
             false => Ok(()),
         }
     }
@@ -163,23 +253,46 @@ impl Session {
             SendChallenge(a, b) => self.handle_send_challenge(n, a, b).await,
             CompleteChallenge(a) => self.handle_complete_challenge(n, a).await,
             CreateAccount(a, b) => self.handle_create_account(n, a, b).await,
-            GetToken(a, b) => self.handle_get_token(n, a, b).await,
+            GetToken(a, b, c, d) => self.handle_get_token(n, a, b, c, d).await,
             OpenSession(a, b) => self.handle_open_session(n, a, b).await,
+            ListTokens => self.handle_list_tokens(n).await,
+            RevokeToken(a) => self.handle_revoke_token(n, a).await,
+            RevokeOtherTokens => self.handle_revoke_other_tokens(n).await,
+            ChangePassword(a, b) => self.handle_change_password(n, a, b).await,
+            ResetPassword(a) => self.handle_reset_password(n, a).await,
+            RedeemResetCode(a, b, c) => self.handle_redeem_reset_code(n, a, b, c).await,
+            IssueResetCode(a) => self.handle_issue_reset_code(n, a).await,
+            SetupTwoFactor => self.handle_setup_two_factor(n).await,
+            EnableTwoFactor(a) => self.handle_enable_two_factor(n, a).await,
+            DisableTwoFactor(a, b) => self.handle_disable_two_factor(n, a, b).await,
+            NewRecoveryCodes(a) => self.handle_new_recovery_codes(n, a).await,
+            ResetTwoFactor(a) => self.handle_reset_two_factor(n, a).await,
+            ListUsers => self.handle_list_users(n).await,
+            ListEntities => self.handle_list_entities(n).await,
+            SetMaxFileSize(a, b) => self.handle_set_max_file_size(n, a, b).await,
+            SetAccountDisabled(a, b) => self.handle_set_account_disabled(n, a, b).await,
+            DeleteAccount(a) => self.handle_delete_account(n, a).await,
+            ForceBackup => self.handle_force_backup(n).await,
+            ServerStats => self.handle_server_stats(n).await,
+            SetStorageQuota(a, b) => self.handle_set_storage_quota(n, a, b).await,
             LoadUserData(a) => self.handle_load_user_data(n, a).await,
             SetUserData(a, b) => self.handle_set_user_data(n, a, b).await,
             OpenInvite(a, b, c) => self.handle_open_invite(n, a, b, c).await,
             WhoIs(a) => self.handle_who_is(n, a).await,
             CreateEntity(a, b) => self.handle_create_entity(n, a, b).await,
             ServerShutdown => self.handle_server_shutdown(n).await,
+            ExecutorMetrics => self.handle_executor_metrics(n).await,
 
             // generic entity actions
-            LoadHistory(a) => self.handle_load_history(n, a).await,
+            LoadHistory(a, b, c) => self.handle_load_history(n, a, b, c).await,
+            SyncSince(a, b) => self.handle_sync_since(n, a, b).await,
             SetLastSeen(a, b) => self.handle_set_last_seen(n, a, b).await,
             SetEntityTags(a, b) => self.handle_set_entity_tags(n, a, b).await,
             RenameEntity(a, b) => self.handle_rename_entity(n, a, b).await,
             CreateInvite(a, b, c) => self.handle_create_invite(n, a, b, c).await,
             TransferOwnership(a, b) => self.handle_transfer_ownership(n, a, b).await,
             BanGuest(a, b) => self.handle_ban_guest(n, a, b).await,
+            UnbanGuest(a, b) => self.handle_unban_guest(n, a, b).await,
             Drop(a) => self.handle_drop(n, a).await,
 
             // conversations
@@ -203,6 +316,8 @@ impl Session {
             DeleteFile(a, b, c) => self.handle_delete_file(n, a, b, c).await,
             // SetFile(a, b, c, d) => self.handle_set_file(n, a, b, c, d).await,
             FinishFile(a, b, c) => self.handle_finish_file(n, a, b, c).await,
+            FileLink(a, b) => self.handle_file_link(n, a, b).await,
+            SetBucketQuota(a, b) => self.handle_set_bucket_quota(n, a, b).await,
         }
     }
 
@@ -226,7 +341,8 @@ impl RwLock<Entity<User>> {
 }
 
 #[derive(Debug)]
-enum Select<A: Debug, B: Debug> {
+enum Select<A: Debug, B: Debug, C: Debug> {
     A(A),
     B(B),
+    C(C),
 }
@@ -4,21 +4,42 @@ use crate::database::entities::{Revision, IndexInEntity};
 use crate::database::update::{Update, UpdateType};
 use crate::database::objects::{
     Message, Cell, Element, MessageExtension,
-    File, ConvId, SheetId, DocumentId, BucketId, Stamp,
+    File, ConvId, SheetId, DocumentId, BucketId, UserId, now_stamp,
 };
+use crate::database::journal::save_storage;
 
 use crate::DATABASE;
+use crate::http::file_link;
+use crate::config::config;
 use super::upload::TemporaryFile;
 use super::requests::MessageCursor;
 use super::replies::{Reply, ReplyData};
 
 use super::{Session, ErrMsg};
 
-use std::time::{SystemTime, Duration};
 use std::mem::{drop, replace, take};
+use std::iter::once;
+
+/// Charges a new copy of a file to its uploader
+async fn charge_storage(user_id: UserId, file: &File) -> Result<(), ErrMsg> {
+    let arc_user = DATABASE.users.find(user_id).await.ok_or("No such user")?;
+    let mut user = arc_user.write().await;
+    let quota = user.secret.storage_quota;
+    user.secret.storage.charge(file, quota)?;
+    save_storage(user_id, &user, &file.sha256);
+    Ok(())
+}
+
+async fn refund_storage(file: &File) {
+    let Some(user_id) = file.uploader else {
+        return;
+    };
 
-fn now_stamp() -> Stamp {
-    SystemTime::UNIX_EPOCH.elapsed().unwrap_or(Duration::ZERO).as_secs()
+    if let Some(arc_user) = DATABASE.users.find(user_id).await {
+        let mut user = arc_user.write().await;
+        user.secret.storage.refund(file);
+        save_storage(user_id, &user, &file.sha256);
+    }
 }
 
 impl Session {
@@ -41,7 +62,7 @@ impl Session {
             MessageCursor::Latest => max,
         };
 
-        let start = stop.saturating_sub(50);
+        let start = stop.saturating_sub(config().messages_per_page);
         let Some(slice) = conv.messages.get(start..stop) else {
             return Err("Invalid cursor");
         };
@@ -80,6 +101,7 @@ impl Session {
         let index = conv.messages.len() as u64;
         let update = Update::new(UpdateType::NewMessage, entity_id, rev, index, &message);
         conv.messages.push(message);
+        conv.commit(user_id, &update);
 
         drop(conv);
         DATABASE.notify_users(update).await;
@@ -129,6 +151,7 @@ impl Session {
         message.content = serde_json::to_string(&extended).unwrap();
         let update = Update::new(UpdateType::SetMessage, entity_id, new_rev, index, message);
         conv.metadata.revision = new_rev;
+        conv.commit(user_id, &update);
 
         drop(conv);
         DATABASE.notify_users(update).await;
@@ -175,6 +198,7 @@ impl Session {
         message.content = serde_json::to_string(&extended).unwrap();
         let update = Update::new(UpdateType::SetMessage, entity_id, new_rev, index, message);
         conv.metadata.revision = new_rev;
+        conv.commit(user_id, &update);
 
         drop(conv);
         DATABASE.notify_users(update).await;
@@ -204,7 +228,7 @@ impl Session {
         index: IndexInEntity,
         cell: Cell,
     ) -> Result<Reply, ErrMsg> {
-        let (arc_user, _user_id) = self.get_user().await?;
+        let (arc_user, user_id) = self.get_user().await?;
         arc_user.check_access_to(EntityId::Spreadsheet(sheet_id), true).await?;
 
         let arc_sheet = DATABASE.sheets.find(sheet_id).await.ok_or("No such spreadsheet")?;
@@ -214,11 +238,26 @@ impl Session {
             return Err("Out of date");
         }
 
-        let update = Update::cell(sheet_id, sheet.metadata.revision, index, &cell);
-        sheet.cells[&index] = cell;
+        sheet.metadata.revision += 1;
+        let rev = sheet.metadata.revision;
+
+        // the edited cell first, then its dependents
+        let dependents = sheet.set_cell(index, cell);
+
+        let updates: Vec<_> = once(index).chain(dependents).map(|i| {
+            Update::cell(sheet_id, rev, i, &sheet.cells[&i])
+        }).collect();
+
+        sheet.commit(user_id, &updates[0]);
+        for update in &updates[1..] {
+            sheet.commit_derived(user_id, update);
+        }
 
         drop(sheet);
-        DATABASE.notify_users(update).await;
+        for update in updates {
+            DATABASE.notify_users(update).await;
+        }
+
         Ok(Reply::new(num, ReplyData::GenericSuccess))
     }
 
@@ -246,7 +285,7 @@ impl Session {
         index: IndexInEntity,
         element: Element,
     ) -> Result<Reply, ErrMsg> {
-        let (arc_user, _user_id) = self.get_user().await?;
+        let (arc_user, user_id) = self.get_user().await?;
         let entity_id = EntityId::Document(doc_id);
         arc_user.check_access_to(entity_id, true).await?;
         let upd_type = UpdateType::NewElement;
@@ -263,6 +302,7 @@ impl Session {
         doc.metadata.revision += 1;
         let update = Update::new(upd_type, entity_id, doc.metadata.revision, index, &element);
         doc.elements.insert(index as usize, element);
+        doc.commit(user_id, &update);
 
         drop(doc);
         DATABASE.notify_users(update).await;
@@ -276,7 +316,7 @@ impl Session {
         rev: Revision,
         index: IndexInEntity,
     ) -> Result<Reply, ErrMsg> {
-        let (arc_user, _user_id) = self.get_user().await?;
+        let (arc_user, user_id) = self.get_user().await?;
         let entity_id = EntityId::Document(doc_id);
         arc_user.check_access_to(entity_id, true).await?;
         let upd_type = UpdateType::ByeElement;
@@ -293,6 +333,7 @@ impl Session {
         doc.metadata.revision += 1;
         let update = Update::new(upd_type, entity_id, doc.metadata.revision, index, &"");
         doc.elements.remove(index as usize);
+        doc.commit(user_id, &update);
 
         drop(doc);
         DATABASE.notify_users(update).await;
@@ -307,7 +348,7 @@ impl Session {
         index: IndexInEntity,
         element: Element,
     ) -> Result<Reply, ErrMsg> {
-        let (arc_user, _user_id) = self.get_user().await?;
+        let (arc_user, user_id) = self.get_user().await?;
         let entity_id = EntityId::Document(doc_id);
         arc_user.check_access_to(entity_id, true).await?;
         let upd_type = UpdateType::SetElement;
@@ -324,6 +365,7 @@ impl Session {
         doc.metadata.revision += 1;
         let update = Update::new(upd_type, entity_id, doc.metadata.revision, index, &element);
         doc.elements[index as usize] = element;
+        doc.commit(user_id, &update);
 
         drop(doc);
         DATABASE.notify_users(update).await;
@@ -343,7 +385,24 @@ impl Session {
         let files = bucket.files.to_vec();
         let rev = bucket.metadata.revision;
 
-        Ok(Reply::new(num, ReplyData::Bucket(rev, files)))
+        Ok(Reply::new(num, ReplyData::Bucket(rev, files, bucket.quota)))
+    }
+
+    pub(super) async fn handle_file_link(
+        &mut self,
+        num: usize,
+        bucket_id: BucketId,
+        index: IndexInEntity,
+    ) -> Result<Reply, ErrMsg> {
+        let (arc_user, user_id) = self.get_user().await?;
+        arc_user.check_access_to(EntityId::Bucket(bucket_id), false).await?;
+
+        let arc_bucket = DATABASE.buckets.find(bucket_id).await.ok_or("No such bucket")?;
+        let bucket = arc_bucket.read().await;
+        let file = bucket.files.get(index as usize).ok_or("No such file")?;
+        let url = file_link(user_id, bucket_id, index, file);
+
+        Ok(Reply::new(num, ReplyData::FileLink(url)))
     }
 
     pub(super) async fn handle_delete_file(
@@ -353,7 +412,7 @@ impl Session {
         rev: Revision,
         index: IndexInEntity,
     ) -> Result<Reply, ErrMsg> {
-        let (arc_user, _user_id) = self.get_user().await?;
+        let (arc_user, user_id) = self.get_user().await?;
         let entity_id = EntityId::Bucket(bucket_id);
         arc_user.check_access_to(entity_id, true).await?;
         let upd_type = UpdateType::ByeFile;
@@ -370,10 +429,12 @@ impl Session {
         bucket.metadata.revision += 1;
         let update = Update::new(upd_type, entity_id, bucket.metadata.revision, index, &"");
         let file = bucket.files.remove(index as usize);
+        bucket.commit(user_id, &update);
 
         drop(bucket);
         DATABASE.notify_users(update).await;
         DATABASE.dec_file_rc(&file.sha256).await;
+        refund_storage(&file).await;
 
         Ok(Reply::new(num, ReplyData::GenericSuccess))
     }
@@ -384,9 +445,9 @@ impl Session {
         bucket_id: BucketId,
         rev: Revision,
         index: Option<IndexInEntity>,
-        file: File,
+        mut file: File,
     ) -> Result<Reply, ErrMsg> {
-        let (arc_user, _user_id) = self.get_user().await?;
+        let (arc_user, user_id) = self.get_user().await?;
         let entity_id = EntityId::Bucket(bucket_id);
         arc_user.check_access_to(entity_id, true).await?;
 
@@ -402,6 +463,14 @@ impl Session {
             return Err("Out of date");
         }
 
+        let replaced = (!new_file).then_some(index as usize);
+        if !bucket.fits(&file, replaced) {
+            return Err("Bucket quota exceeded");
+        }
+
+        file.uploader = Some(user_id);
+        charge_storage(user_id, &file).await?;
+
         let upd_type = match new_file {
             true => UpdateType::NewFile,
             false => UpdateType::SetFile,
@@ -419,11 +488,14 @@ impl Session {
             Some(replace(mem_loc, file))
         };
 
+        bucket.commit(user_id, &update);
+
         drop(bucket);
         DATABASE.notify_users(update).await;
 
         if let Some(old_file) = maybe_old_file {
             DATABASE.dec_file_rc(&old_file.sha256).await;
+            refund_storage(&old_file).await;
         }
 
         Ok(Reply::new(num, ReplyData::GenericSuccess))
@@ -449,8 +521,35 @@ impl Session {
             sha256,
             size,
             uploaded: now_stamp(),
+            uploader: None, // set by handle_set_file
         };
 
         self.handle_set_file(num, bucket_id, rev, None, file_data).await
     }
-}
\ No newline at end of file
+
+    pub(super) async fn handle_set_bucket_quota(
+        &mut self,
+        num: usize,
+        bucket_id: BucketId,
+        quota: Option<usize>,
+    ) -> Result<Reply, ErrMsg> {
+        let (_arc_user, user_id) = self.get_user().await?;
+        let entity_id = EntityId::Bucket(bucket_id);
+
+        let arc_bucket = DATABASE.buckets.find(bucket_id).await.ok_or("No such bucket")?;
+        let mut bucket = arc_bucket.write().await;
+        if bucket.metadata.author != user_id {
+            return Err("User is not the author of this entity");
+        }
+
+        bucket.quota = quota;
+        bucket.metadata.revision += 1;
+        let update = Update::new(UpdateType::SetBucketQuota, entity_id, bucket.metadata.revision, 0, &quota);
+        bucket.commit(user_id, &update);
+
+        drop(bucket);
+        DATABASE.notify_users(update).await;
+
+        Ok(Reply::new(num, ReplyData::GenericSuccess))
+    }
+}
@@ -2,13 +2,17 @@ use serde::Serialize;
 
 use crate::database::{
     EntityId,
-    entities::{Revision, IndexInEntity},
+    update::Update,
+    entities::{Revision, IndexInEntity, Change},
     objects::{
         Message, Token, Cell, UserData, Element, AssociatedImage,
-        File, UserId, SecretUserData,
+        File, UserId, SecretUserData, TokenInfo,
     },
 };
 
+use crate::executor::Metrics;
+
+use super::admin::{UserSummary, EntitySummary, ServerStats};
 use super::EntitiesDataMap;
 
 #[derive(Debug, Clone, Serialize)]
@@ -22,16 +26,28 @@ pub struct Reply {
 #[serde(tag = "reply", content = "parameters")]
 #[serde(rename_all = "kebab-case")]
 pub enum ReplyData {
-    AuthenticationToken(Token),
+    Tokens(Vec<TokenInfo>),
+    Credentials(UserId, Token),
     ValidUsername(UserId),
     UserData(Revision, UserData, AssociatedImage),
-    SelfData(Revision, UserData, EntitiesDataMap, SecretUserData),
+    SelfData(Revision, UserData, EntitiesDataMap, Box<SecretUserData>),
     EntityCreated(EntityId),
-    // History(Vec<(Revision, Change)>),
+    History(Vec<(Revision, Change)>),
+    Updates(Revision, Vec<Update>),
     Messages(Revision, IndexInEntity, Vec<Message>),
     Spreadsheet(Revision, Vec<(IndexInEntity, Cell)>),
     Document(Revision, Vec<Element>),
-    Bucket(Revision, Vec<File>),
+    /// revision, files, quota
+    Bucket(Revision, Vec<File>, Option<usize>),
+    FileLink(String),
+    ResetCode(String),
+    /// secret, provisioning URI
+    TwoFactorSetup(String, String),
+    RecoveryCodes(Vec<String>),
+    ExecutorMetrics(Metrics),
+    Users(Vec<UserSummary>),
+    Entities(Vec<EntitySummary>),
+    ServerStats(ServerStats),
     GenericSuccess,
     GenericFailure(String),
 }
@@ -44,6 +60,3 @@ impl Reply {
         }
     }
 }
-
-#[derive(Debug, Clone, Serialize)]
-pub struct Change;
\ No newline at end of file
@@ -5,7 +5,7 @@ use crate::database::{
     entities::{Revision, EntityTag, IndexInEntity},
     objects::{
         Token, Cell, UserData, Email, Username, Element,
-        UserId, ConvId, SheetId, DocumentId, BucketId,
+        UserId, ConvId, SheetId, DocumentId, BucketId, TokenId,
     },
 };
 
@@ -30,23 +30,60 @@ pub enum RequestData {
     SendChallenge(Email, ChallengeTarget),
     CompleteChallenge(Code),
     CreateAccount(Username, String),
-    GetToken(UserId, String),
+    /// username, password, device label, two-factor or recovery code
+    GetToken(Username, String, String, Option<Code>),
     OpenSession(UserId, Token),
+    ListTokens,
+    /// None revokes the token of this session
+    RevokeToken(Option<TokenId>),
+    /// Logs out every other device
+    RevokeOtherTokens,
+    /// old password, new password
+    ChangePassword(String, String),
+    /// After completing a `PasswordReset` challenge
+    ResetPassword(String),
+    /// username, code issued by an admin, new password
+    RedeemResetCode(Username, Code, String),
+    IssueResetCode(UserId),
+    /// Replies with a new TOTP secret, enabled by `EnableTwoFactor`
+    SetupTwoFactor,
+    EnableTwoFactor(Code),
+    /// password, two-factor or recovery code
+    DisableTwoFactor(String, Code),
+    NewRecoveryCodes(Code),
+    /// For users who lost their authenticator
+    ResetTwoFactor(UserId),
     LoadUserData(Option<UserId>),
     SetUserData(Revision, UserData),
     OpenInvite(Revision, Invite, Discard),
     WhoIs(Username),
     CreateEntity(EntityType, String),
     ServerShutdown,
+    ExecutorMetrics,
+
+    // administration
+    ListUsers,
+    ListEntities,
+    SetMaxFileSize(UserId, usize),
+    /// user, disabled
+    SetAccountDisabled(UserId, bool),
+    DeleteAccount(UserId),
+    /// Schedules a snapshot of the database
+    ForceBackup,
+    ServerStats,
+    SetStorageQuota(UserId, usize),
 
     // generic entity actions
-    LoadHistory(EntityId),
+    /// entity, since revision, limit
+    LoadHistory(EntityId, Revision, usize),
+    SyncSince(EntityId, Revision),
     SetLastSeen(EntityId, Revision),
     SetEntityTags(EntityId, Vec<EntityTag>),
     RenameEntity(EntityId, String),
     CreateInvite(EntityId, ReadOnly, Vec<UserId>),
     TransferOwnership(EntityId, Username),
     BanGuest(EntityId, Username),
+    UnbanGuest(EntityId, Username),
     Drop(EntityId),
 
     // conversations
@@ -70,12 +107,17 @@ pub enum RequestData {
     DeleteFile(BucketId, Revision, IndexInEntity),
     // SetFile(BucketId, Revision, Option<IndexInEntity>, File),
     FinishFile(BucketId, Revision, String),
+    FileLink(BucketId, IndexInEntity),
+    /// Author only; None removes the quota
+    SetBucketQuota(BucketId, Option<usize>),
 }
 
-#[derive(Debug, Copy, Clone, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
 pub enum ChallengeTarget {
     AccountCreation,
     Login,
+    EmailUpdate,
+    PasswordReset,
 }
 
 #[derive(Debug, Copy, Clone, Deserialize)]
@@ -0,0 +1,212 @@
+use async_lock::Mutex;
+
+use crate::database::objects::{UserId, Email};
+
+use super::ErrMsg;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+/// Requests a key can make in a burst
+const BURST: f64 = 10.0;
+/// One request is given back every REFILL_PERIOD
+const REFILL_PERIOD: Duration = Duration::from_secs(2);
+/// Failures allowed before lockouts begin
+const FREE_FAILURES: u32 = 3;
+/// The first lockout; each further failure doubles it
+const BASE_LOCKOUT: Duration = Duration::from_secs(2);
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Idle records are forgotten after this long
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Authentication requests are limited per peer address
+/// and per targeted account or email address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Peer(IpAddr),
+    Account(UserId),
+    Email(Email),
+}
+
+impl Key {
+    /// IPv6 peers usually own a whole /64
+    pub fn peer(addr: IpAddr) -> Self {
+        match addr.to_canonical() {
+            IpAddr::V6(v6) => {
+                let prefix = u128::from(v6) & !(u64::MAX as u128);
+                Self::Peer(IpAddr::V6(prefix.into()))
+            },
+            v4 => Self::Peer(v4),
+        }
+    }
+
+    pub fn email(email: &str) -> Self {
+        Self::Email(email.to_lowercase())
+    }
+}
+
+struct Record {
+    tokens: f64,
+    last_seen: Instant,
+    failures: u32,
+    locked_until: Option<Instant>,
+}
+
+impl Record {
+    fn new(now: Instant) -> Self {
+        Self {
+            tokens: BURST,
+            last_seen: now,
+            failures: 0,
+            locked_until: None,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now - self.last_seen;
+        let refilled = elapsed.as_secs_f64() / REFILL_PERIOD.as_secs_f64();
+        self.tokens = (self.tokens + refilled).min(BURST);
+        self.last_seen = now;
+    }
+}
+
+static RECORDS: LazyLock<Mutex<HashMap<Key, Record>>> = LazyLock::new(Default::default);
+
+/// Consumes one request from each key, or fails if any of
+/// them is locked out or out of requests.
+pub async fn attempt(keys: &[Key]) -> Result<(), ErrMsg> {
+    let now = Instant::now();
+    let mut records = RECORDS.lock().await;
+
+    if records.len() > PRUNE_THRESHOLD {
+        records.retain(|_, record| now - record.last_seen < FORGET_AFTER);
+    }
+
+    for key in keys {
+        let record = records.entry(key.clone()).or_insert_with(|| Record::new(now));
+        record.refill(now);
+
+        if record.locked_until.is_some_and(|until| now < until) {
+            return Err("Too many failed attempts, try again later");
+        }
+
+        if record.tokens < 1.0 {
+            return Err("Too many requests, slow down");
+        }
+    }
+
+    for key in keys {
+        records.get_mut(key).unwrap().tokens -= 1.0;
+    }
+
+    Ok(())
+}
+
+/// Records a failed attempt; lockouts grow exponentially
+pub async fn failure(keys: &[Key]) {
+    let now = Instant::now();
+    let mut records = RECORDS.lock().await;
+
+    for key in keys {
+        let record = records.entry(key.clone()).or_insert_with(|| Record::new(now));
+        record.failures += 1;
+
+        if let Some(excess) = record.failures.checked_sub(FREE_FAILURES + 1) {
+            let factor = 1u32.checked_shl(excess).unwrap_or(u32::MAX);
+            let lockout = BASE_LOCKOUT.saturating_mul(factor).min(MAX_LOCKOUT);
+            record.locked_until = Some(now + lockout);
+        }
+    }
+}
+
+/// Forgets the failures of a key after a successful attempt
+pub async fn success(key: Key) {
+    if let Some(record) = RECORDS.lock().await.get_mut(&key) {
+        record.failures = 0;
+        record.locked_until = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::future::block_on;
+
+    // records are shared by tests: each one uses accounts of its own
+
+    fn locked_for(key: &Key) -> Duration {
+        let records = block_on(RECORDS.lock());
+        let until = records[key].locked_until.unwrap();
+        until.saturating_duration_since(Instant::now())
+    }
+
+    #[test]
+    fn bursts_are_limited() {
+        let keys = [Key::Account(1_000)];
+        for _ in 0..BURST as usize {
+            assert!(block_on(attempt(&keys)).is_ok());
+        }
+
+        assert_eq!(block_on(attempt(&keys)), Err("Too many requests, slow down"));
+    }
+
+    #[test]
+    fn nothing_is_consumed_when_a_key_is_exhausted() {
+        let fresh = Key::Account(2_000);
+        let exhausted = Key::Account(2_001);
+        for _ in 0..BURST as usize {
+            block_on(attempt(std::slice::from_ref(&exhausted))).unwrap();
+        }
+
+        assert!(block_on(attempt(&[fresh.clone(), exhausted])).is_err());
+        let tokens = block_on(RECORDS.lock())[&fresh].tokens;
+        assert!(tokens >= BURST - 0.01);
+    }
+
+    #[test]
+    fn lockouts_grow_then_reset() {
+        let key = Key::Account(3_000);
+        let keys = [key.clone()];
+
+        for _ in 0..FREE_FAILURES {
+            block_on(failure(&keys));
+        }
+
+        assert!(block_on(attempt(&keys)).is_ok());
+
+        block_on(failure(&keys));
+        assert_eq!(block_on(attempt(&keys)), Err("Too many failed attempts, try again later"));
+        assert!(locked_for(&key) <= BASE_LOCKOUT);
+        assert!(locked_for(&key) > BASE_LOCKOUT / 2);
+
+        block_on(failure(&keys));
+        assert!(locked_for(&key) > BASE_LOCKOUT);
+
+        for _ in 0..40 {
+            block_on(failure(&keys));
+        }
+
+        assert!(locked_for(&key) <= MAX_LOCKOUT);
+        assert!(locked_for(&key) > MAX_LOCKOUT / 2);
+
+        block_on(success(key.clone()));
+        assert!(block_on(attempt(&keys)).is_ok());
+    }
+
+    #[test]
+    fn peers_are_grouped() {
+        let a: IpAddr = "2001:db8::1".parse().unwrap();
+        let b: IpAddr = "2001:db8::ffff:2".parse().unwrap();
+        let other: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+        assert_eq!(Key::peer(a), Key::peer(b));
+        assert_ne!(Key::peer(a), Key::peer(other));
+
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        assert_eq!(Key::peer(mapped), Key::peer(v4));
+        assert_eq!(Key::email("Alice@Example.com"), Key::email("alice@example.com"));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::database::objects::Hash;
 use crate::to_hex;
+use crate::config::config;
 
 use async_fs::{rename, remove_file};
 use async_fs::{File, OpenOptions};
@@ -21,7 +22,7 @@ impl TemporaryFile {
 
         let (handle, tmp_path) = loop {
             let rubbish: u32 = random();
-            let tmp_path = format!("files/tmp-{:08x?}.dat", rubbish);
+            let tmp_path = config().file_path(&format!("tmp-{:08x?}", rubbish));
 
             let mut options = OpenOptions::new();
             options.write(true);
@@ -50,11 +51,11 @@ impl TemporaryFile {
     }
 
     pub async fn finalize(mut self) -> (Hash, usize) {
-        let _ = self.handle.close();
+        let _ = self.handle.close().await;
 
         let hash = to_hex(self.hasher.finalize().into());
 
-        let final_path = format!("files/{}.dat", hash);
+        let final_path = config().file_path(&hash);
 
         if let Err(error) = rename(&self.tmp_path, final_path).await {
             if error.kind() == ErrorKind::AlreadyExists {
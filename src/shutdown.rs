@@ -0,0 +1,91 @@
+use async_signal::{Signal, Signals};
+use async_channel::{Sender, Receiver, unbounded};
+use async_io::Timer;
+
+use crate::{StreamExt, trigger_backup, or};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// Sessions get this long to notify their client and finish their request
+const GRACE_PERIOD_SECONDS: u64 = 5;
+
+/// Closed when the server starts going down
+static CLOSING: LazyLock<(Sender<()>, Receiver<()>)> = LazyLock::new(unbounded);
+static SHUTDOWN_REQUEST: LazyLock<(Sender<()>, Receiver<()>)> = LazyLock::new(unbounded);
+static OPEN_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Held by every running session
+pub struct SessionGuard(());
+
+impl SessionGuard {
+    pub fn new() -> Self {
+        OPEN_SESSIONS.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        OPEN_SESSIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Connected clients, logged in or not
+pub fn open_sessions() -> usize {
+    OPEN_SESSIONS.load(Ordering::SeqCst)
+}
+
+pub fn is_closing() -> bool {
+    CLOSING.0.is_closed()
+}
+
+/// Resolves once the server starts going down
+pub async fn closing() {
+    let _ = CLOSING.1.recv().await;
+}
+
+/// Asks the shutdown task to stop the server
+pub fn request() {
+    let _ = SHUTDOWN_REQUEST.0.try_send(());
+}
+
+/// Waits for SIGTERM, SIGINT or a `ServerShutdown` request, then
+/// closes sessions and writes the database before exiting.
+pub async fn shutdown_task() {
+    let mut signals = Signals::new([Signal::Term, Signal::Int]).unwrap();
+
+    let signal = async {
+        let signal = signals.next().await;
+        println!("received {:?}", signal);
+    };
+
+    let request = async {
+        let _ = SHUTDOWN_REQUEST.1.recv().await;
+        println!("received a shutdown request");
+    };
+
+    or(signal, request).await;
+    println!("shutting down");
+
+    // stops the listener and notifies sessions
+    CLOSING.0.close();
+
+    let sessions_closed = async {
+        while OPEN_SESSIONS.load(Ordering::SeqCst) > 0 {
+            Timer::after(Duration::from_millis(50)).await;
+        }
+    };
+
+    let timeout = async {
+        Timer::after(Duration::from_secs(GRACE_PERIOD_SECONDS)).await;
+        let open = OPEN_SESSIONS.load(Ordering::SeqCst);
+        println!("{} sessions still open after the grace period", open);
+    };
+
+    or(sessions_closed, timeout).await;
+
+    // the snapshot waits for in-flight requests
+    trigger_backup().await;
+}
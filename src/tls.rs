@@ -0,0 +1,244 @@
+use futures_lite::{AsyncRead, AsyncWrite, ready};
+use async_net::TcpStream;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::pki_types::pem::PemObject;
+use rustls::crypto::ring::default_provider;
+
+use crate::config::config;
+
+use std::io::{self, Read, Write, ErrorKind};
+use std::task::{Context, Poll};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::pin::Pin;
+
+/// Connection to a client or a server, encrypted or not
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream>),
+}
+
+pub struct TlsStream {
+    tcp: TcpStream,
+    conn: Connection,
+}
+
+/// Builds the TLS configuration if a certificate is configured
+pub fn server_config() -> Option<Arc<ServerConfig>> {
+    let config = config();
+    let cert_path = config.tls_cert.as_ref()?;
+    let key_path = config.tls_key.as_ref().expect("tls_cert is set but tls_key isn't");
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .expect("couldn't read TLS certificate");
+
+    let key = PrivateKeyDer::from_pem_file(key_path).expect("couldn't read TLS key");
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .expect("invalid TLS certificate or key");
+
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Some(Arc::new(server_config))
+}
+
+/// Trusts the root certificates of a PEM file, for outgoing connections
+pub fn client_config(ca_file: &str) -> Arc<ClientConfig> {
+    let certs = CertificateDer::pem_file_iter(ca_file)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .expect("couldn't read CA certificates");
+
+    let mut roots = RootCertStore::empty();
+    let (added, _ignored) = roots.add_parsable_certificates(certs);
+    assert!(added > 0, "no valid CA certificate in {}", ca_file);
+
+    let client_config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Arc::new(client_config)
+}
+
+impl Stream {
+    pub fn new(tcp: TcpStream, tls: Option<&Arc<ServerConfig>>) -> io::Result<Self> {
+        let Some(tls) = tls else {
+            return Ok(Self::Plain(tcp));
+        };
+
+        let conn = ServerConnection::new(tls.clone()).map_err(io::Error::other)?;
+        Ok(Self::Tls(Box::new(TlsStream { tcp, conn: conn.into() })))
+    }
+
+    /// Encrypts a connection to `host`; the handshake
+    /// happens along with the first read or write.
+    pub fn client(tcp: TcpStream, tls: &Arc<ClientConfig>, host: &str) -> io::Result<Self> {
+        let name = ServerName::try_from(host.to_string()).map_err(io::Error::other)?;
+        let conn = ClientConnection::new(tls.clone(), name).map_err(io::Error::other)?;
+        Ok(Self::Tls(Box::new(TlsStream { tcp, conn: conn.into() })))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Plain(tcp) => tcp.peer_addr(),
+            Self::Tls(tls) => tls.tcp.peer_addr(),
+        }
+    }
+}
+
+/// Lets rustls use a non-blocking socket
+struct SyncIo<'a, 'b> {
+    tcp: &'a mut TcpStream,
+    cx: &'a mut Context<'b>,
+}
+
+impl Read for SyncIo<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.tcp).poll_read(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for SyncIo<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.tcp).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.tcp).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl TlsStream {
+    /// Receives TLS records; returns 0 at the end of the TCP stream
+    fn poll_read_tls(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let mut io = SyncIo { tcp: &mut self.tcp, cx };
+
+        let length = match self.conn.read_tls(&mut io) {
+            Ok(length) => length,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Poll::Pending,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+
+        if let Err(e) = self.conn.process_new_packets() {
+            // try to tell the client why
+            let _ = self.conn.write_tls(&mut io);
+            return Poll::Ready(Err(io::Error::new(ErrorKind::InvalidData, e)));
+        }
+
+        Poll::Ready(Ok(length))
+    }
+
+    /// Sends every pending TLS record
+    fn poll_write_tls(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut io = SyncIo { tcp: &mut self.tcp, cx };
+
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut io) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Poll::Pending,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            match this.conn.reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                result => return Poll::Ready(result),
+            }
+
+            // handshake messages, alerts, etc.
+            ready!(this.poll_write_tls(cx))?;
+            ready!(this.poll_read_tls(cx))?;
+        }
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            let length = this.conn.writer().write(buf)?;
+
+            if length > 0 || buf.is_empty() {
+                // records which can't be sent yet are sent by the next flush
+                if let Poll::Ready(Err(e)) = this.poll_write_tls(cx) {
+                    return Poll::Ready(Err(e));
+                }
+
+                return Poll::Ready(Ok(length));
+            }
+
+            ready!(this.poll_write_tls(cx))?;
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.conn.writer().flush()?;
+        ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.tcp).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.conn.send_close_notify();
+        ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.tcp).poll_close(cx)
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            Self::Tls(tls) => Pin::new(&mut **tls).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            Self::Tls(tls) => Pin::new(&mut **tls).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(tcp) => Pin::new(tcp).poll_flush(cx),
+            Self::Tls(tls) => Pin::new(&mut **tls).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(tcp) => Pin::new(tcp).poll_close(cx),
+            Self::Tls(tls) => Pin::new(&mut **tls).poll_close(cx),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds per time step, as expected by authenticator apps
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+/// Steps accepted before and after the current one, for clock drift
+const SKEW: u64 = 1;
+const SECRET_LEN: usize = 20;
+const ISSUER: &str = "Kolab";
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// New random secret, base32-encoded
+pub fn new_secret() -> String {
+    let bytes: [u8; SECRET_LEN] = rand::random();
+    let mut secret = String::new();
+
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer.iter().fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 31;
+            secret.push(BASE32[index as usize] as char);
+        }
+    }
+
+    secret
+}
+
+fn decode(secret: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut bits = 0u32;
+    let mut len = 0;
+
+    for c in secret.bytes().filter(|c| *c != b'=') {
+        let value = BASE32.iter().position(|b| *b == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u32;
+        len += 5;
+
+        if len >= 8 {
+            len -= 8;
+            bytes.push((bits >> len) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+/// URI to scan or paste into an authenticator app
+pub fn provisioning_uri(secret: &str, account: &str) -> String {
+    let label = format!("{}:{}", ISSUER, account);
+    format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(&label), secret, ISSUER, DIGITS, STEP,
+    )
+}
+
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            true => encoded.push(byte as char),
+            false => encoded += &format!("%{:02X}", byte),
+        }
+    }
+
+    encoded
+}
+
+/// RFC 4226 code for one counter value
+fn code_at(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[19] & 0xf) as usize;
+    let bytes = [digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]];
+    let truncated = u32::from_be_bytes(bytes) & 0x7fff_ffff;
+
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// Returns the time step matching `code`, if it's
+/// more recent than `last_step` (codes can't be replayed).
+pub fn verify(secret: &str, code: &str, last_step: u64) -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    verify_at(secret, code, last_step, now)
+}
+
+/// `now` in seconds since the Unix epoch
+fn verify_at(secret: &str, code: &str, last_step: u64, now: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+
+    let code: u32 = code.parse().ok()?;
+    let key = decode(secret)?;
+    let current = now / STEP;
+
+    let steps = current.saturating_sub(SKEW)..=current + SKEW;
+    steps.filter(|step| *step > last_step).find(|step| code_at(&key, *step) == code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "12345678901234567890", the SHA-1 key of RFC 6238
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    /// Times and codes of RFC 6238, appendix B, cut to 6 digits
+    const RFC_VECTORS: [(u64, &str); 6] = [
+        (59, "287082"),
+        (1111111109, "081804"),
+        (1111111111, "050471"),
+        (1234567890, "005924"),
+        (2000000000, "279037"),
+        (20000000000, "353130"),
+    ];
+
+    #[test]
+    fn rfc_6238_vectors() {
+        assert_eq!(decode(RFC_SECRET).unwrap(), b"12345678901234567890");
+
+        for (time, code) in RFC_VECTORS {
+            assert_eq!(verify_at(RFC_SECRET, code, 0, time), Some(time / STEP));
+        }
+    }
+
+    #[test]
+    fn codes_cant_be_replayed() {
+        let (time, code) = RFC_VECTORS[3];
+        let step = verify_at(RFC_SECRET, code, 0, time).unwrap();
+
+        assert_eq!(verify_at(RFC_SECRET, code, step, time), None);
+        assert_eq!(verify_at(RFC_SECRET, code, step - 1, time), Some(step));
+    }
+
+    #[test]
+    fn clocks_may_drift_by_one_step() {
+        let (time, code) = RFC_VECTORS[3];
+        assert!(verify_at(RFC_SECRET, code, 0, time + STEP).is_some());
+        assert!(verify_at(RFC_SECRET, code, 0, time - STEP).is_some());
+        assert!(verify_at(RFC_SECRET, code, 0, time + 2 * STEP).is_none());
+        assert!(verify_at(RFC_SECRET, code, 0, time - 2 * STEP).is_none());
+    }
+
+    #[test]
+    fn malformed_codes_are_rejected() {
+        let (time, code) = RFC_VECTORS[3];
+        assert_eq!(verify_at(RFC_SECRET, &format!(" {code} "), 0, time), Some(time / STEP));
+        assert_eq!(verify_at(RFC_SECRET, &code[1..], 0, time), None);
+        assert_eq!(verify_at(RFC_SECRET, "abcdef", 0, time), None);
+        assert_eq!(verify_at("not base32!", code, 0, time), None);
+    }
+
+    #[test]
+    fn secrets_round_trip() {
+        let secret = new_secret();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(decode(&secret).unwrap().len(), SECRET_LEN);
+        assert!(provisioning_uri(&secret, "alice smith").starts_with("otpauth://totp/Kolab%3Aalice%20smith?secret="));
+    }
+}